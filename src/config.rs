@@ -0,0 +1,642 @@
+//! Configuration file loading for xtabbie.
+//!
+//! The config lives at `$XDG_CONFIG_HOME/xtabbie/config.toml` and uses a small
+//! subset of TOML: `[section]` headers, `key = value` pairs, `#` comments, and
+//! integer, float, boolean, string and single-line array values.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::keys::{keysym_from_name, Action, Binding, Hotkey, Keysym, XK_ESCAPE, XK_RETURN, XK_TAB};
+
+/// How windows are arranged in the popup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutStyle {
+    /// Icon grid with the selected title in a title bar.
+    Grid,
+    /// One row per window with a small icon, title and class.
+    List,
+}
+
+/// Layout settings for the switcher popup.
+#[derive(Debug, Clone)]
+pub struct LayoutConfig {
+    pub style: LayoutStyle,
+    pub icon_size: u16,
+    /// Icon size in the list layout.
+    pub list_icon_size: u16,
+    /// Show a short title under each icon in the grid layout.
+    pub captions: bool,
+    pub padding: u16,
+    pub title_height: u16,
+    pub max_cols: u16,
+    /// Maximum popup width as a fraction of the monitor width.
+    pub max_width: f32,
+    /// Maximum popup height as a fraction of the monitor height.
+    pub max_height: f32,
+}
+
+impl Default for LayoutConfig {
+    fn default() -> Self {
+        LayoutConfig {
+            style: LayoutStyle::Grid,
+            icon_size: 48,
+            list_icon_size: 24,
+            captions: false,
+            padding: 8,
+            title_height: 24,
+            max_cols: 20,
+            max_width: 0.8,
+            max_height: 0.8,
+        }
+    }
+}
+
+impl LayoutConfig {
+    /// Size icons are rendered at for the configured style.
+    pub fn cell_icon_size(&self) -> u16 {
+        match self.style {
+            LayoutStyle::Grid => self.icon_size,
+            LayoutStyle::List => self.list_icon_size,
+        }
+    }
+}
+
+/// How window icons are rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IconStyle {
+    /// Posterized black and white, the classic look.
+    Bw,
+    /// Full color on TrueColor visuals.
+    Color,
+    /// A few shades of gray, for displays with limited palettes.
+    Gray,
+}
+
+/// How shading is approximated when reducing icons to few levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    /// Round each pixel to the nearest level.
+    None,
+    /// Error diffusion, best for photo-like icons.
+    FloydSteinberg,
+    /// Ordered 4x4 Bayer pattern, a regular cross-hatch look.
+    Bayer,
+}
+
+/// Icon rendering settings.
+#[derive(Debug, Clone)]
+pub struct IconsConfig {
+    pub style: IconStyle,
+    /// Dithering for the `bw` and `gray` styles.
+    pub dither: Dither,
+    /// Number of gray shades in the `gray` style, black and white included.
+    pub gray_levels: u8,
+}
+
+impl Default for IconsConfig {
+    fn default() -> Self {
+        IconsConfig {
+            style: IconStyle::Bw,
+            dither: Dither::None,
+            gray_levels: 4,
+        }
+    }
+}
+
+/// Keys used while the switcher is open.
+#[derive(Debug, Clone)]
+pub struct KeysConfig {
+    pub next: Keysym,
+    pub select: Keysym,
+    pub cancel: Keysym,
+    /// Close the selected window (WM_DELETE_WINDOW).
+    pub close: Vec<Hotkey>,
+    /// Disconnect the selected window's client.
+    pub kill: Vec<Hotkey>,
+    /// Iconify the selected window.
+    pub iconify: Vec<Hotkey>,
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        KeysConfig {
+            next: XK_TAB,
+            select: XK_RETURN,
+            cancel: XK_ESCAPE,
+            close: default_keys(&["Delete", "Ctrl+q"]),
+            kill: default_keys(&["Ctrl+Delete"]),
+            iconify: default_keys(&["Ctrl+m"]),
+        }
+    }
+}
+
+fn default_keys(names: &[&str]) -> Vec<Hotkey> {
+    names
+        .iter()
+        .map(|name| Hotkey::parse(name).expect("default key is valid"))
+        .collect()
+}
+
+/// Switcher behavior settings.
+#[derive(Debug, Clone)]
+pub struct BehaviorConfig {
+    /// Start with the previously used window selected (traditional alt-tab).
+    pub select_previous: bool,
+    /// Show one entry per application, expanded on demand.
+    pub group_by_class: bool,
+}
+
+impl Default for BehaviorConfig {
+    fn default() -> Self {
+        BehaviorConfig {
+            select_previous: true,
+            group_by_class: false,
+        }
+    }
+}
+
+/// Complete xtabbie configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub layout: LayoutConfig,
+    pub icons: IconsConfig,
+    pub keys: KeysConfig,
+    pub behavior: BehaviorConfig,
+    /// Global hotkeys grabbed in daemon mode.
+    pub hotkeys: Vec<Binding>,
+    /// Alternatives tried for an action when all of its hotkeys are taken.
+    pub fallback_hotkeys: Vec<Binding>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            layout: LayoutConfig::default(),
+            icons: IconsConfig::default(),
+            keys: KeysConfig::default(),
+            behavior: BehaviorConfig::default(),
+            hotkeys: default_hotkeys(),
+            fallback_hotkeys: Vec::new(),
+        }
+    }
+}
+
+fn default_hotkeys() -> Vec<Binding> {
+    [
+        ("Alt+Tab", Action::Next),
+        ("Alt+Shift+Tab", Action::Previous),
+        ("Alt+grave", Action::SameClass),
+    ]
+        .into_iter()
+        .map(|(name, action)| Binding {
+            hotkey: Hotkey::parse(name).expect("default hotkey is valid"),
+            action,
+        })
+        .collect()
+}
+
+/// Error raised while reading or parsing the config file.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.path.display(), self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.path.display(), self.line, self.message)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A parsed config value.
+#[derive(Debug, Clone)]
+enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Describe the value for error messages.
+    fn describe(&self) -> String {
+        match self {
+            Value::Int(i) => format!("integer {}", i),
+            Value::Float(f) => format!("float {}", f),
+            Value::Bool(b) => format!("boolean {}", b),
+            Value::Str(s) => format!("string \"{}\"", s),
+            Value::Array(_) => "array".into(),
+        }
+    }
+}
+
+/// Get the config file path following XDG Base Directory spec.
+pub fn get_config_path() -> PathBuf {
+    let config_home = std::env::var("XDG_CONFIG_HOME").unwrap_or_else(|_| {
+        let home = std::env::var("HOME").unwrap_or_else(|_| "/tmp".into());
+        format!("{}/.config", home)
+    });
+    PathBuf::from(config_home).join("xtabbie").join("config.toml")
+}
+
+/// Load the config from the default path, using defaults if the file is missing.
+pub fn load() -> Result<Config, ConfigError> {
+    load_from(&get_config_path())
+}
+
+/// Load the config from a specific path, using defaults if the file is missing.
+pub fn load_from(path: &Path) -> Result<Config, ConfigError> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => {
+            return Err(ConfigError {
+                path: path.to_path_buf(),
+                line: 0,
+                message: e.to_string(),
+            })
+        }
+    };
+
+    parse(&text).map_err(|(line, message)| ConfigError {
+        path: path.to_path_buf(),
+        line,
+        message,
+    })
+}
+
+/// Parse config text. Errors carry the 1-based line number.
+fn parse(text: &str) -> Result<Config, (usize, String)> {
+    let mut config = Config::default();
+    let mut section = String::new();
+
+    for (idx, raw) in text.lines().enumerate() {
+        let lineno = idx + 1;
+        let line = strip_comment(raw).trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix('[') {
+            let name = rest
+                .strip_suffix(']')
+                .ok_or_else(|| (lineno, "unterminated section header".to_string()))?;
+            section = name.trim().to_string();
+            if !is_known_section(&section) {
+                return Err((lineno, format!("unknown section [{}]", section)));
+            }
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| (lineno, format!("expected `key = value`, found `{}`", line)))?;
+        let key = key.trim();
+        let value = parse_value(value.trim()).map_err(|msg| (lineno, msg))?;
+
+        apply(&mut config, &section, key, value).map_err(|msg| (lineno, msg))?;
+    }
+
+    Ok(config)
+}
+
+fn is_known_section(name: &str) -> bool {
+    matches!(name, "layout" | "icons" | "keys" | "behavior" | "hotkeys" | "fallback")
+}
+
+/// Strip a trailing `#` comment, ignoring `#` inside quoted strings.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    if s.is_empty() {
+        return Err("missing value".into());
+    }
+
+    if s == "true" {
+        return Ok(Value::Bool(true));
+    }
+    if s == "false" {
+        return Ok(Value::Bool(false));
+    }
+
+    if s.starts_with('"') {
+        return parse_string(s).map(Value::Str);
+    }
+
+    if let Some(rest) = s.strip_prefix('[') {
+        let inner = rest.strip_suffix(']').ok_or("unterminated array")?;
+        let items = split_array_items(inner)
+            .into_iter()
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                if item.starts_with('[') {
+                    Err("nested arrays are not supported".to_string())
+                } else {
+                    parse_value(item)
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(Value::Array(items));
+    }
+
+    if let Ok(i) = s.parse::<i64>() {
+        return Ok(Value::Int(i));
+    }
+    if let Ok(f) = s.parse::<f64>() {
+        return Ok(Value::Float(f));
+    }
+
+    Err(format!("invalid value `{}` (strings must be quoted)", s))
+}
+
+/// Split array contents on commas outside quoted strings.
+fn split_array_items(inner: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut in_string = false;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ',' if !in_string => {
+                items.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&inner[start..]);
+    items
+}
+
+fn parse_string(s: &str) -> Result<String, String> {
+    s.strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .filter(|inner| !inner.contains('"'))
+        .map(str::to_string)
+        .ok_or_else(|| format!("invalid string `{}`", s))
+}
+
+fn apply(config: &mut Config, section: &str, key: &str, value: Value) -> Result<(), String> {
+    match (section, key) {
+        ("layout", "style") => config.layout.style = expect_style(value)?,
+        ("layout", "icon_size") => config.layout.icon_size = expect_range(value, 8, 256)?,
+        ("layout", "list_icon_size") => config.layout.list_icon_size = expect_range(value, 16, 128)?,
+        ("layout", "captions") => config.layout.captions = expect_bool(value)?,
+        ("layout", "padding") => config.layout.padding = expect_range(value, 0, 64)?,
+        ("layout", "title_height") => config.layout.title_height = expect_range(value, 16, 128)?,
+        ("layout", "max_cols") => config.layout.max_cols = expect_range(value, 1, 256)?,
+        ("layout", "max_width") => config.layout.max_width = expect_fraction(value)?,
+        ("layout", "max_height") => config.layout.max_height = expect_fraction(value)?,
+        ("icons", "style") => config.icons.style = expect_icon_style(value)?,
+        ("icons", "dither") => config.icons.dither = expect_dither(value)?,
+        ("icons", "gray_levels") => config.icons.gray_levels = expect_range(value, 2, 16)?,
+        ("keys", "next") => config.keys.next = expect_keysym(value)?,
+        ("keys", "select") => config.keys.select = expect_keysym(value)?,
+        ("keys", "cancel") => config.keys.cancel = expect_keysym(value)?,
+        ("keys", "close") => config.keys.close = expect_hotkeys(value)?,
+        ("keys", "kill") => config.keys.kill = expect_hotkeys(value)?,
+        ("keys", "iconify") => config.keys.iconify = expect_hotkeys(value)?,
+        ("behavior", "select_previous") => config.behavior.select_previous = expect_bool(value)?,
+        ("behavior", "group_by_class") => config.behavior.group_by_class = expect_bool(value)?,
+        ("hotkeys", "next") => set_hotkeys(&mut config.hotkeys, Action::Next, value)?,
+        ("hotkeys", "previous") => set_hotkeys(&mut config.hotkeys, Action::Previous, value)?,
+        ("hotkeys", "same_class") => set_hotkeys(&mut config.hotkeys, Action::SameClass, value)?,
+        ("hotkeys", "persistent") => set_hotkeys(&mut config.hotkeys, Action::Persistent, value)?,
+        ("fallback", "next") => set_hotkeys(&mut config.fallback_hotkeys, Action::Next, value)?,
+        ("fallback", "previous") => set_hotkeys(&mut config.fallback_hotkeys, Action::Previous, value)?,
+        ("fallback", "same_class") => set_hotkeys(&mut config.fallback_hotkeys, Action::SameClass, value)?,
+        ("fallback", "persistent") => set_hotkeys(&mut config.fallback_hotkeys, Action::Persistent, value)?,
+        ("", _) => return Err(format!("key `{}` must be inside a section", key)),
+        _ => return Err(format!("unknown key `{}` in [{}]", key, section)),
+    }
+    Ok(())
+}
+
+fn expect_range<T: TryFrom<i64>>(value: Value, min: i64, max: i64) -> Result<T, String> {
+    match value {
+        Value::Int(i) if (min..=max).contains(&i) => {
+            T::try_from(i).map_err(|_| format!("value {} out of range", i))
+        }
+        Value::Int(i) => Err(format!("value {} out of range ({}..={})", i, min, max)),
+        other => Err(format!("expected integer, found {}", other.describe())),
+    }
+}
+
+fn expect_fraction(value: Value) -> Result<f32, String> {
+    let f = match value {
+        Value::Float(f) => f,
+        Value::Int(i) => i as f64,
+        other => return Err(format!("expected number, found {}", other.describe())),
+    };
+    if f > 0.0 && f <= 1.0 {
+        Ok(f as f32)
+    } else {
+        Err(format!("value {} out of range (0.0 < x <= 1.0)", f))
+    }
+}
+
+fn expect_bool(value: Value) -> Result<bool, String> {
+    match value {
+        Value::Bool(b) => Ok(b),
+        other => Err(format!("expected boolean, found {}", other.describe())),
+    }
+}
+
+fn expect_style(value: Value) -> Result<LayoutStyle, String> {
+    match value {
+        Value::Str(s) if s == "grid" => Ok(LayoutStyle::Grid),
+        Value::Str(s) if s == "list" => Ok(LayoutStyle::List),
+        other => Err(format!("expected \"grid\" or \"list\", found {}", other.describe())),
+    }
+}
+
+fn expect_icon_style(value: Value) -> Result<IconStyle, String> {
+    match value {
+        Value::Str(s) if s == "bw" => Ok(IconStyle::Bw),
+        Value::Str(s) if s == "color" => Ok(IconStyle::Color),
+        Value::Str(s) if s == "gray" => Ok(IconStyle::Gray),
+        other => Err(format!("expected \"bw\", \"color\" or \"gray\", found {}", other.describe())),
+    }
+}
+
+fn expect_dither(value: Value) -> Result<Dither, String> {
+    match value {
+        Value::Str(s) if s == "none" => Ok(Dither::None),
+        Value::Str(s) if s == "floyd-steinberg" => Ok(Dither::FloydSteinberg),
+        Value::Str(s) if s == "bayer" => Ok(Dither::Bayer),
+        other => Err(format!(
+            "expected \"none\", \"floyd-steinberg\" or \"bayer\", found {}",
+            other.describe()
+        )),
+    }
+}
+
+fn expect_keysym(value: Value) -> Result<Keysym, String> {
+    match value {
+        Value::Str(name) => keysym_from_name(&name).ok_or_else(|| format!("unknown keysym `{}`", name)),
+        other => Err(format!("expected keysym name, found {}", other.describe())),
+    }
+}
+
+/// Parse a hotkey string or an array of them.
+fn expect_hotkeys(value: Value) -> Result<Vec<Hotkey>, String> {
+    let items = match value {
+        Value::Array(items) => items,
+        other => vec![other],
+    };
+
+    items
+        .into_iter()
+        .map(|item| match item {
+            Value::Str(s) => Hotkey::parse(&s),
+            other => Err(format!("expected hotkey string, found {}", other.describe())),
+        })
+        .collect()
+}
+
+/// Replace all bindings for an action with the hotkeys listed in `value`.
+fn set_hotkeys(bindings: &mut Vec<Binding>, action: Action, value: Value) -> Result<(), String> {
+    let hotkeys = expect_hotkeys(value)?;
+
+    bindings.retain(|b| b.action != action);
+    bindings.extend(hotkeys.into_iter().map(|hotkey| Binding { hotkey, action }));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_err(text: &str) -> (usize, String) {
+        parse(text).expect_err("config should be rejected")
+    }
+
+    fn key_names(hotkeys: &[Hotkey]) -> Vec<&str> {
+        hotkeys.iter().map(|h| h.name.as_str()).collect()
+    }
+
+    #[test]
+    fn empty_text_gives_defaults() {
+        let config = parse("").unwrap();
+        assert_eq!(config.layout.icon_size, 48);
+        assert_eq!(config.keys.next, XK_TAB);
+        assert_eq!(config.hotkeys.len(), 3);
+    }
+
+    #[test]
+    fn reads_values_of_every_type() {
+        let config = parse(
+            "# comment line\n\
+             [layout]\n\
+             style = \"list\"\n\
+             icon_size = 32   # trailing comment\n\
+             max_width = 0.5\n\
+             max_height = 1\n\
+             captions = true\n\
+             \n\
+             [icons]\n\
+             style = \"gray\"\n\
+             dither = \"bayer\"\n\
+             gray_levels = 8\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.layout.style, LayoutStyle::List);
+        assert_eq!(config.layout.icon_size, 32);
+        assert_eq!(config.layout.max_width, 0.5);
+        assert_eq!(config.layout.max_height, 1.0);
+        assert!(config.layout.captions);
+        assert_eq!(config.icons.style, IconStyle::Gray);
+        assert_eq!(config.icons.dither, Dither::Bayer);
+        assert_eq!(config.icons.gray_levels, 8);
+    }
+
+    #[test]
+    fn hash_inside_string_is_not_a_comment() {
+        let config = parse("[keys]\nclose = \"Ctrl+numbersign\" # comment\nnext = \"#\"\n").unwrap();
+        assert_eq!(key_names(&config.keys.close), ["Ctrl+numbersign"]);
+        assert_eq!(config.keys.next, '#' as Keysym);
+    }
+
+    #[test]
+    fn arrays_replace_bindings() {
+        let config = parse("[keys]\nclose = [\"Delete\", \"Ctrl+w\",]\n[hotkeys]\nnext = [\"Super+Tab\"]\n").unwrap();
+        assert_eq!(key_names(&config.keys.close), ["Delete", "Ctrl+w"]);
+
+        let next: Vec<&str> = config
+            .hotkeys
+            .iter()
+            .filter(|b| b.action == Action::Next)
+            .map(|b| b.hotkey.name.as_str())
+            .collect();
+        assert_eq!(next, ["Super+Tab"]);
+        assert!(config.hotkeys.iter().any(|b| b.action == Action::Previous));
+    }
+
+    #[test]
+    fn comma_inside_array_string_is_kept() {
+        let config = parse("[keys]\nclose = [\"Ctrl+,\", \"Delete\"]\n").unwrap();
+        assert_eq!(key_names(&config.keys.close), ["Ctrl+,", "Delete"]);
+        assert_eq!(config.keys.close[0].keysym, ',' as Keysym);
+    }
+
+    #[test]
+    fn errors_report_their_line() {
+        let (line, message) = parse_err("[layout]\n\n# padding next\npadding = 100\n");
+        assert_eq!(line, 4);
+        assert_eq!(message, "value 100 out of range (0..=64)");
+
+        assert_eq!(parse_err("[layout\n").0, 1);
+        assert_eq!(parse_err("[layout]\nicon_size\n").0, 2);
+    }
+
+    #[test]
+    fn unknown_sections_and_keys_are_rejected() {
+        assert_eq!(parse_err("[colors]\n"), (1, "unknown section [colors]".to_string()));
+        assert_eq!(
+            parse_err("[layout]\nicon_sise = 32\n"),
+            (2, "unknown key `icon_sise` in [layout]".to_string())
+        );
+        assert_eq!(
+            parse_err("icon_size = 32\n"),
+            (1, "key `icon_size` must be inside a section".to_string())
+        );
+    }
+
+    #[test]
+    fn type_and_range_errors_name_the_value() {
+        assert_eq!(parse_err("[layout]\nicon_size = \"big\"\n").1, "expected integer, found string \"big\"");
+        assert_eq!(parse_err("[layout]\ncaptions = 1\n").1, "expected boolean, found integer 1");
+        assert_eq!(parse_err("[layout]\nmax_width = 1.5\n").1, "value 1.5 out of range (0.0 < x <= 1.0)");
+        assert_eq!(parse_err("[layout]\nstyle = grid\n").1, "invalid value `grid` (strings must be quoted)");
+        assert_eq!(parse_err("[keys]\nnext = \"NoSuchKey\"\n").1, "unknown keysym `NoSuchKey`");
+    }
+
+    #[test]
+    fn malformed_strings_and_arrays_are_rejected() {
+        assert_eq!(parse_err("[keys]\nnext = \"Tab\n").1, "invalid string `\"Tab`");
+        assert_eq!(parse_err("[keys]\nclose = [\"Delete\"\n").1, "unterminated array");
+        assert_eq!(parse_err("[keys]\nclose = [[\"Delete\"]]\n").1, "nested arrays are not supported");
+    }
+}
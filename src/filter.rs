@@ -0,0 +1,91 @@
+//! Fuzzy matching for the switcher's type-to-filter search.
+//!
+//! A query matches a string if its characters appear in order, ignoring case.
+//! Matches score higher when characters are adjacent, start a word or start
+//! the string, so "xt" ranks "xterm" above "firefox - Text Editor".
+
+/// Bonus for a match at the very start of the string.
+const START_BONUS: i32 = 8;
+/// Bonus for a match right after a separator such as a space or `-`.
+const WORD_BONUS: i32 = 6;
+/// Bonus for a match directly following the previous matched character.
+const ADJACENT_BONUS: i32 = 4;
+/// Base score for every matched character.
+const MATCH_SCORE: i32 = 1;
+/// Penalty per skipped character between two matches.
+const GAP_PENALTY: i32 = 1;
+
+/// Score `text` against `query`, or None if the query doesn't match.
+/// An empty query matches everything with a score of 0.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i32> {
+    let mut query = query.chars().flat_map(char::to_lowercase).peekable();
+    let mut score = 0;
+    let mut last_match: Option<usize> = None;
+    let mut prev: Option<char> = None;
+
+    for (i, c) in text.chars().enumerate() {
+        let Some(&wanted) = query.peek() else {
+            break;
+        };
+
+        if c.to_lowercase().eq(std::iter::once(wanted)) {
+            score += MATCH_SCORE;
+            match prev {
+                None => score += START_BONUS,
+                Some(p) if !p.is_alphanumeric() => score += WORD_BONUS,
+                _ => {}
+            }
+            match last_match {
+                Some(last) if last + 1 == i => score += ADJACENT_BONUS,
+                Some(last) => score -= GAP_PENALTY * (i - last - 1).min(8) as i32,
+                None => {}
+            }
+            last_match = Some(i);
+            query.next();
+        }
+
+        prev = Some(c);
+    }
+
+    query.peek().is_none().then_some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_characters_in_order() {
+        assert!(fuzzy_score("xtm", "xterm").is_some());
+        assert!(fuzzy_score("mx", "xterm").is_none());
+        assert!(fuzzy_score("xterms", "xterm").is_none());
+        assert!(fuzzy_score("q", "xterm").is_none());
+    }
+
+    #[test]
+    fn empty_query_matches_with_zero_score() {
+        assert_eq!(fuzzy_score("", "xterm"), Some(0));
+        assert_eq!(fuzzy_score("", ""), Some(0));
+    }
+
+    #[test]
+    fn ignores_case() {
+        assert_eq!(fuzzy_score("XT", "xterm"), fuzzy_score("xt", "xterm"));
+        assert_eq!(fuzzy_score("emacs", "GNU Emacs"), fuzzy_score("EMACS", "gnu emacs"));
+        assert!(fuzzy_score("édit", "Éditeur").is_some());
+    }
+
+    #[test]
+    fn prefix_ranks_above_scattered_match() {
+        let xterm = fuzzy_score("xt", "xterm").unwrap();
+        let editor = fuzzy_score("xt", "firefox - Text Editor").unwrap();
+        assert!(xterm > editor, "{} <= {}", xterm, editor);
+    }
+
+    #[test]
+    fn word_start_ranks_above_mid_word() {
+        let word = fuzzy_score("co", "VS Code").unwrap();
+        let mid_word = fuzzy_score("co", "decode").unwrap();
+        assert!(word > mid_word, "{} <= {}", word, mid_word);
+    }
+}
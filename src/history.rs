@@ -0,0 +1,143 @@
+//! Focus history tracking for true MRU window ordering.
+//!
+//! Many minimal WMs don't raise windows on focus, so stacking order is not a
+//! reliable MRU order. The daemon watches focus changes on every top-level
+//! window and `_NET_ACTIVE_WINDOW` on the root, and remembers the order in
+//! which top-levels were focused.
+
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::*;
+use x11rb::protocol::Event;
+
+use crate::window::{ewmh_wm_check, find_toplevel_parent};
+
+/// Maximum number of top-levels remembered.
+const MAX_HISTORY: usize = 256;
+
+/// Top-level windows in the order they were focused, most recent first.
+pub struct FocusHistory {
+    root: Window,
+    net_active_window: Atom,
+    order: Vec<Window>,
+}
+
+impl FocusHistory {
+    /// Create an empty history. Call `watch` to start tracking focus changes.
+    pub fn new(conn: &impl Connection, root: Window) -> FocusHistory {
+        let net_active_window = conn
+            .intern_atom(false, b"_NET_ACTIVE_WINDOW")
+            .ok()
+            .and_then(|c| c.reply().ok())
+            .map(|r| r.atom)
+            .unwrap_or(x11rb::NONE);
+
+        FocusHistory {
+            root,
+            net_active_window,
+            order: Vec::new(),
+        }
+    }
+
+    /// Select the events needed to follow focus: property and substructure
+    /// changes on the root, and focus changes on every existing top-level.
+    pub fn watch(&mut self, conn: &impl Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.change_window_attributes(
+            self.root,
+            &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE | EventMask::SUBSTRUCTURE_NOTIFY),
+        )?;
+
+        let tree = conn.query_tree(self.root)?.reply()?;
+        for child in tree.children {
+            watch_toplevel(conn, child);
+        }
+        conn.flush()?;
+
+        self.record_current(conn);
+        Ok(())
+    }
+
+    /// Update the history from a daemon or popup event; other events are ignored.
+    pub fn handle_event(&mut self, conn: &impl Connection, event: &Event) {
+        match event {
+            Event::CreateNotify(ev) if ev.parent == self.root => {
+                watch_toplevel(conn, ev.window);
+                let _ = conn.flush();
+            }
+            Event::DestroyNotify(ev) => {
+                self.order.retain(|&w| w != ev.window);
+            }
+            Event::FocusIn(ev) if ev.event != self.root && is_real_focus_change(ev) => {
+                self.record(ev.event);
+            }
+            Event::PropertyNotify(ev) if ev.window == self.root && ev.atom == self.net_active_window => {
+                if let Some(active) = self.active_window(conn) {
+                    self.record(find_toplevel_parent(conn, active, self.root));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Record whichever window currently has focus, in case an event was missed.
+    /// Uses `_NET_ACTIVE_WINDOW` under an EWMH WM, the input focus otherwise.
+    pub fn record_current(&mut self, conn: &impl Connection) {
+        let focus = self.active_window(conn).or_else(|| {
+            conn.get_input_focus()
+                .ok()
+                .and_then(|c| c.reply().ok())
+                .map(|r| r.focus)
+                .filter(|&w| w != x11rb::NONE && w != self.root && w != u32::from(InputFocus::POINTER_ROOT))
+        });
+
+        if let Some(window) = focus {
+            self.record(find_toplevel_parent(conn, window, self.root));
+        }
+    }
+
+    /// Move a top-level to the front of the history.
+    fn record(&mut self, toplevel: Window) {
+        if self.order.first() == Some(&toplevel) {
+            return;
+        }
+        log_fmt!("Focus history: 0x{:x} focused", toplevel);
+        self.order.retain(|&w| w != toplevel);
+        self.order.insert(0, toplevel);
+        self.order.truncate(MAX_HISTORY);
+    }
+
+    /// The most recently focused top-level, if any.
+    pub fn current(&self) -> Option<Window> {
+        self.order.first().copied()
+    }
+
+    /// Position of a top-level in the history, or None if never seen focused.
+    pub fn position(&self, toplevel: Window) -> Option<usize> {
+        self.order.iter().position(|&w| w == toplevel)
+    }
+
+    /// Read `_NET_ACTIVE_WINDOW` from the root, if an EWMH WM is running to
+    /// maintain it. A value left behind by a previous WM is ignored.
+    fn active_window(&self, conn: &impl Connection) -> Option<Window> {
+        if self.net_active_window == x11rb::NONE || ewmh_wm_check(conn, self.root).is_err() {
+            return None;
+        }
+
+        let prop = conn
+            .get_property(false, self.root, self.net_active_window, AtomEnum::WINDOW, 0, 1)
+            .ok()?
+            .reply()
+            .ok()?;
+
+        let window = prop.value32()?.next();
+        window.filter(|&w| w != x11rb::NONE)
+    }
+}
+
+/// Ignore the focus shuffles caused by keyboard grabs, including our own.
+fn is_real_focus_change(ev: &FocusInEvent) -> bool {
+    ev.mode == NotifyMode::NORMAL || ev.mode == NotifyMode::WHILE_GRABBED
+}
+
+fn watch_toplevel(conn: &impl Connection, window: Window) {
+    let _ = conn.change_window_attributes(window, &ChangeWindowAttributesAux::new().event_mask(EventMask::FOCUS_CHANGE));
+}
@@ -1,7 +1,13 @@
 //! Icon handling for window switcher - fetching, converting, and rendering.
 
+use std::collections::HashMap;
+
 use x11rb::connection::Connection;
+use x11rb::properties::WmHints;
 use x11rb::protocol::xproto::*;
+use x11rb::protocol::Event;
+
+use crate::config::{Dither, IconStyle, IconsConfig};
 
 /// Represents a 1-bit black and white icon.
 pub struct BwIcon {
@@ -11,30 +17,714 @@ pub struct BwIcon {
     pub data: Vec<bool>,
 }
 
-impl BwIcon {
-    /// Scale the icon to target size using nearest neighbor.
-    pub fn scale(&self, target_size: u16) -> BwIcon {
-        let mut scaled = Vec::with_capacity((target_size * target_size) as usize);
+/// A full-color icon as 32-bit ARGB pixels, as found in _NET_WM_ICON.
+#[derive(Clone)]
+pub struct ArgbIcon {
+    pub width: u16,
+    pub height: u16,
+    pub pixels: Vec<u32>,
+}
+
+impl ArgbIcon {
+    /// Scale the icon to fit a `target_size` square, keeping its aspect ratio
+    /// and centering it on a transparent background. Large icons are box
+    /// filtered; small ones are enlarged by a whole factor when it fits at
+    /// least twice, so pixel art stays crisp, and bilinearly otherwise.
+    pub fn scale(&self, target_size: u16) -> ArgbIcon {
+        let (width, height) = (self.width as u32, self.height as u32);
+        let target = target_size as u32;
+        let longest = width.max(height).max(1);
+
+        let fit = |n: u32| ((n * target / longest).max(1)) as u16;
+        let resized = if target / longest >= 2 {
+            self.scale_integer((target / longest) as u16)
+        } else if longest > target {
+            self.scale_box(fit(width), fit(height))
+        } else if longest < target {
+            self.scale_bilinear(fit(width), fit(height))
+        } else {
+            self.clone()
+        };
+
+        resized.letterbox(target_size)
+    }
+
+    /// Enlarge by a whole factor, repeating each pixel.
+    fn scale_integer(&self, factor: u16) -> ArgbIcon {
+        let (width, height) = (self.width * factor, self.height * factor);
+        let pixels = (0..height as usize)
+            .flat_map(|y| (0..width as usize).map(move |x| (y, x)))
+            .map(|(y, x)| self.pixel(x / factor as usize, y / factor as usize))
+            .collect();
+        ArgbIcon { width, height, pixels }
+    }
+
+    /// Shrink by averaging the source area under each target pixel.
+    fn scale_box(&self, width: u16, height: u16) -> ArgbIcon {
+        let sx = self.width as f32 / width as f32;
+        let sy = self.height as f32 / height as f32;
+        let mut pixels = Vec::with_capacity(width as usize * height as usize);
+
+        for y in 0..height as usize {
+            let (top, bottom) = (y as f32 * sy, (y + 1) as f32 * sy);
+            for x in 0..width as usize {
+                let (left, right) = (x as f32 * sx, (x + 1) as f32 * sx);
+                let mut sum = [0.0f32; 4];
+                let mut area = 0.0;
+
+                for src_y in top as usize..(bottom.ceil() as usize).min(self.height as usize) {
+                    let cover_y = (bottom.min(src_y as f32 + 1.0) - top.max(src_y as f32)).max(0.0);
+                    for src_x in left as usize..(right.ceil() as usize).min(self.width as usize) {
+                        let cover = cover_y * (right.min(src_x as f32 + 1.0) - left.max(src_x as f32)).max(0.0);
+                        let p = premultiply(self.pixel(src_x, src_y));
+                        for (s, c) in sum.iter_mut().zip(p) {
+                            *s += c * cover;
+                        }
+                        area += cover;
+                    }
+                }
+
+                pixels.push(unpremultiply(sum.map(|s| s / area.max(f32::EPSILON))));
+            }
+        }
+
+        ArgbIcon { width, height, pixels }
+    }
 
-        for y in 0..target_size {
-            for x in 0..target_size {
-                let src_x = (x as u32 * self.width as u32 / target_size as u32) as usize;
-                let src_y = (y as u32 * self.height as u32 / target_size as u32) as usize;
-                let idx = src_y * self.width as usize + src_x;
-                scaled.push(self.data.get(idx).copied().unwrap_or(false));
+    /// Enlarge by interpolating between the four nearest source pixels.
+    fn scale_bilinear(&self, width: u16, height: u16) -> ArgbIcon {
+        let sx = self.width as f32 / width as f32;
+        let sy = self.height as f32 / height as f32;
+        let max_x = self.width as f32 - 1.0;
+        let max_y = self.height as f32 - 1.0;
+        let mut pixels = Vec::with_capacity(width as usize * height as usize);
+
+        for y in 0..height {
+            let fy = ((y as f32 + 0.5) * sy - 0.5).clamp(0.0, max_y);
+            let (y0, ty) = (fy as usize, fy.fract());
+            let y1 = (y0 + 1).min(self.height as usize - 1);
+            for x in 0..width {
+                let fx = ((x as f32 + 0.5) * sx - 0.5).clamp(0.0, max_x);
+                let (x0, tx) = (fx as usize, fx.fract());
+                let x1 = (x0 + 1).min(self.width as usize - 1);
+
+                let lerp = |a: [f32; 4], b: [f32; 4], t: f32| std::array::from_fn(|i| a[i] + (b[i] - a[i]) * t);
+                let top = lerp(premultiply(self.pixel(x0, y0)), premultiply(self.pixel(x1, y0)), tx);
+                let bottom = lerp(premultiply(self.pixel(x0, y1)), premultiply(self.pixel(x1, y1)), tx);
+                pixels.push(unpremultiply(lerp(top, bottom, ty)));
             }
         }
 
+        ArgbIcon { width, height, pixels }
+    }
+
+    /// Center the icon on a transparent square of `size` pixels.
+    fn letterbox(&self, size: u16) -> ArgbIcon {
+        let off_x = size.saturating_sub(self.width) as usize / 2;
+        let off_y = size.saturating_sub(self.height) as usize / 2;
+        let mut pixels = vec![0; size as usize * size as usize];
+
+        for y in 0..(self.height.min(size) as usize) {
+            for x in 0..(self.width.min(size) as usize) {
+                pixels[(y + off_y) * size as usize + x + off_x] = self.pixel(x, y);
+            }
+        }
+
+        ArgbIcon {
+            width: size,
+            height: size,
+            pixels,
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> u32 {
+        self.pixels.get(y * self.width as usize + x).copied().unwrap_or(0)
+    }
+
+    /// Posterize to B&W, dithered or with a hard threshold.
+    pub fn to_bw(&self, dither: Dither) -> BwIcon {
+        let levels = argb_to_levels(&self.pixels, self.width as usize, 2, dither);
         BwIcon {
-            width: target_size,
-            height: target_size,
-            data: scaled,
+            width: self.width,
+            height: self.height,
+            data: levels.into_iter().map(|level| level == 0).collect(),
+        }
+    }
+}
+
+/// Turns window icons into server-side pixmaps in the configured style.
+pub struct IconRenderer {
+    drawable: Drawable,
+    colormap: Colormap,
+    size: u16,
+    dither: Dither,
+    mode: RenderMode,
+    /// Root pixel layout, for reading full-depth WM_HINTS icon pixmaps.
+    format: Option<PixelFormat>,
+}
+
+/// Pixel conversion chosen for the screen.
+enum RenderMode {
+    Bw,
+    Color(PixelFormat),
+    /// Gray shades, darkest first, as pixel values.
+    Gray(PixelFormat, Vec<u32>),
+}
+
+impl IconRenderer {
+    /// Create a renderer for icons of `size` pixels on `screen`. Color needs
+    /// a TrueColor root visual and gray needs its shades allocated; both
+    /// fall back to B&W otherwise. Gray shades stay allocated for as long as
+    /// the connection, so create one renderer and reuse it for every popup.
+    pub fn new(conn: &impl Connection, screen: &Screen, size: u16, config: &IconsConfig) -> IconRenderer {
+        let format = PixelFormat::for_screen(conn.setup(), screen);
+        let mode = match config.style {
+            IconStyle::Bw => None,
+            IconStyle::Color => format.clone().filter(|f| f.true_color).map(RenderMode::Color),
+            IconStyle::Gray => format.clone().and_then(|f| {
+                let palette = gray_palette(conn, screen, &f, config.gray_levels)?;
+                Some(RenderMode::Gray(f, palette))
+            }),
+        };
+
+        if mode.is_none() && config.style != IconStyle::Bw {
+            log_fmt!("Icon style {:?} not supported on this screen, using B&W icons", config.style);
+        }
+
+        IconRenderer {
+            drawable: screen.root,
+            colormap: screen.default_colormap,
+            size,
+            dither: config.dither,
+            mode: mode.unwrap_or(RenderMode::Bw),
+            format,
+        }
+    }
+
+    /// Fetch a window's icon and upload it. Falls back to the WM_HINTS icon
+    /// pixmap, then to the generic icon.
+    pub fn render(&self, conn: &impl Connection, window: Window) -> Result<IconPixmap, Box<dyn std::error::Error>> {
+        let icon = get_window_icon(conn, window, self.size).or_else(|| self.get_wm_hints_icon(conn, window));
+        let Some(icon) = icon else {
+            return IconPixmap::upload_bitmap(conn, self.drawable, &create_generic_icon(self.size));
+        };
+
+        match &self.mode {
+            RenderMode::Bw => IconPixmap::upload_bitmap(conn, self.drawable, &icon.to_bw(self.dither)),
+            RenderMode::Color(format) => {
+                let values: Vec<u32> = icon.pixels.iter().map(|&argb| format.pixel(blend_on_white(argb))).collect();
+                IconPixmap::upload_pixels(conn, self.drawable, &icon, &values, format)
+            }
+            RenderMode::Gray(format, palette) => {
+                let levels = argb_to_levels(&icon.pixels, icon.width as usize, palette.len() as u8, self.dither);
+                let values: Vec<u32> = levels.into_iter().map(|level| palette[level as usize]).collect();
+                IconPixmap::upload_pixels(conn, self.drawable, &icon, &values, format)
+            }
+        }
+    }
+
+    /// Read the legacy WM_HINTS icon_pixmap, cut out by icon_mask, as set by
+    /// classic clients such as xterm and xclock instead of _NET_WM_ICON.
+    fn get_wm_hints_icon(&self, conn: &impl Connection, window: Window) -> Option<ArgbIcon> {
+        let hints = WmHints::get(conn, window).ok()?.reply().ok()??;
+        let pixmap = hints.icon_pixmap.filter(|&p| p != x11rb::NONE)?;
+        let (width, height, depth) = pixmap_geometry(conn, pixmap)?;
+
+        let mut pixels: Vec<u32> = if depth == 1 {
+            get_bitmap(conn, pixmap, width, height)?
+                .into_iter()
+                .map(|black| if black { 0xff00_0000 } else { 0xffff_ffff })
+                .collect()
+        } else {
+            let Some(format) = self.format.as_ref().filter(|f| f.depth == depth) else {
+                log_fmt!("Skipping WM_HINTS icon of window 0x{:x}: unsupported depth {}", window, depth);
+                return None;
+            };
+            let image = conn
+                .get_image(ImageFormat::Z_PIXMAP, pixmap, 0, 0, width, height, !0)
+                .ok()?
+                .reply()
+                .ok()?;
+            let values = format.decode(width, height, &image.data)?;
+            self.pixels_to_argb(conn, format, &values)?
+        };
+
+        // Without a usable mask the whole pixmap is opaque
+        let mask = hints.icon_mask.filter(|&m| m != x11rb::NONE).and_then(|mask| {
+            let (mask_width, mask_height, depth) = pixmap_geometry(conn, mask)?;
+            if depth != 1 {
+                return None;
+            }
+            let bits = get_bitmap(conn, mask, mask_width, mask_height)?;
+            Some((mask_width as usize, mask_height as usize, bits))
+        });
+        if let Some((mask_width, mask_height, bits)) = mask {
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                let (y, x) = (i / width as usize, i % width as usize);
+                if x >= mask_width || y >= mask_height || !bits[y * mask_width + x] {
+                    *pixel = 0;
+                }
+            }
+        }
+
+        let icon = ArgbIcon { width, height, pixels };
+        Some(icon.scale(self.size))
+    }
+
+    /// Turn pixel values of the root visual into opaque ARGB, looking colors
+    /// up in the default colormap when the visual isn't TrueColor.
+    fn pixels_to_argb(&self, conn: &impl Connection, format: &PixelFormat, values: &[u32]) -> Option<Vec<u32>> {
+        let argb = |(r, g, b): (u8, u8, u8)| 0xff00_0000 | (r as u32) << 16 | (g as u32) << 8 | b as u32;
+
+        if format.true_color {
+            return Some(values.iter().map(|&v| argb(format.rgb(v))).collect());
         }
+
+        let mut unique = values.to_vec();
+        unique.sort_unstable();
+        unique.dedup();
+        let reply = conn.query_colors(self.colormap, &unique).ok()?.reply().ok()?;
+        let colors: Vec<(u8, u8, u8)> = reply
+            .colors
+            .iter()
+            .map(|c| ((c.red >> 8) as u8, (c.green >> 8) as u8, (c.blue >> 8) as u8))
+            .collect();
+
+        values
+            .iter()
+            .map(|v| unique.binary_search(v).ok().and_then(|i| colors.get(i)).map(|&c| argb(c)))
+            .collect()
+    }
+}
+
+/// Icon pixmaps of client windows, kept for the life of the daemon so each
+/// icon is fetched, converted and uploaded only once. An entry is dropped
+/// when its client is destroyed or changes `_NET_WM_ICON` or `WM_HINTS`.
+pub struct IconCache {
+    renderer: IconRenderer,
+    net_wm_icon: Atom,
+    icons: HashMap<Window, IconPixmap>,
+}
+
+impl IconCache {
+    pub fn new(conn: &impl Connection, renderer: IconRenderer) -> IconCache {
+        let net_wm_icon = conn
+            .intern_atom(false, b"_NET_WM_ICON")
+            .ok()
+            .and_then(|c| c.reply().ok())
+            .map(|r| r.atom)
+            .unwrap_or(x11rb::NONE);
+
+        IconCache {
+            renderer,
+            net_wm_icon,
+            icons: HashMap::new(),
+        }
+    }
+
+    /// Get a client's icon, rendering it on first use. The pixmap stays owned
+    /// by the cache; callers must not free it.
+    pub fn get(&mut self, conn: &impl Connection, window: Window) -> Result<IconPixmap, Box<dyn std::error::Error>> {
+        if let Some(&icon) = self.icons.get(&window) {
+            return Ok(icon);
+        }
+
+        watch_client(conn, window);
+        let icon = self.renderer.render(conn, window)?;
+        self.icons.insert(window, icon);
+        Ok(icon)
+    }
+
+    /// Drop the icon of a destroyed client, or of one whose icon properties
+    /// changed. Returns the client in the latter case, so it can be redrawn.
+    pub fn handle_event(&mut self, conn: &impl Connection, event: &Event) -> Option<Window> {
+        let (window, changed) = match event {
+            Event::DestroyNotify(ev) => (ev.window, false),
+            Event::PropertyNotify(ev) if ev.atom == self.net_wm_icon || ev.atom == u32::from(AtomEnum::WM_HINTS) => {
+                (ev.window, true)
+            }
+            _ => return None,
+        };
+
+        let icon = self.icons.remove(&window)?;
+        log_fmt!("Icon cache: dropping icon of 0x{:x}", window);
+        let _ = icon.free(conn);
+        changed.then_some(window)
     }
 }
 
-/// Fetch _NET_WM_ICON and convert to B&W with hard threshold.
-pub fn get_window_icon(conn: &impl Connection, window: Window, target_size: u16) -> Option<BwIcon> {
+/// Follow a client's property changes and destruction, keeping any events
+/// already selected on it (e.g. focus changes on a non-reparented client).
+/// A client that is already gone is skipped.
+fn watch_client(conn: &impl Connection, window: Window) {
+    let Some(attrs) = conn.get_window_attributes(window).ok().and_then(|c| c.reply().ok()) else {
+        return;
+    };
+    let mask = attrs.your_event_mask | EventMask::PROPERTY_CHANGE | EventMask::STRUCTURE_NOTIFY;
+    let _ = conn.change_window_attributes(window, &ChangeWindowAttributesAux::new().event_mask(mask));
+}
+
+/// Layout of pixels in a ZPixmap image of the root depth.
+#[derive(Clone)]
+struct PixelFormat {
+    depth: u8,
+    bits_per_pixel: u8,
+    scanline_pad: u8,
+    /// Root visual is TrueColor, so pixel values come from the color masks.
+    true_color: bool,
+    red_mask: u32,
+    green_mask: u32,
+    blue_mask: u32,
+    lsb_first: bool,
+}
+
+impl PixelFormat {
+    /// Inspect the root visual and the matching pixmap format. Returns None
+    /// for pixel sizes other than whole bytes.
+    fn for_screen(setup: &Setup, screen: &Screen) -> Option<PixelFormat> {
+        let visual = screen
+            .allowed_depths
+            .iter()
+            .flat_map(|d| &d.visuals)
+            .find(|v| v.visual_id == screen.root_visual)?;
+
+        let format = setup.pixmap_formats.iter().find(|f| f.depth == screen.root_depth)?;
+        if !matches!(format.bits_per_pixel, 8 | 16 | 24 | 32) {
+            return None;
+        }
+
+        log_fmt!(
+            "Root visual: class {:?}, depth {}, {} bpp, masks r=0x{:x} g=0x{:x} b=0x{:x}",
+            visual.class,
+            screen.root_depth,
+            format.bits_per_pixel,
+            visual.red_mask,
+            visual.green_mask,
+            visual.blue_mask
+        );
+
+        Some(PixelFormat {
+            depth: screen.root_depth,
+            bits_per_pixel: format.bits_per_pixel,
+            scanline_pad: format.scanline_pad,
+            true_color: visual.class == VisualClass::TRUE_COLOR,
+            red_mask: visual.red_mask,
+            green_mask: visual.green_mask,
+            blue_mask: visual.blue_mask,
+            lsb_first: setup.image_byte_order == ImageOrder::LSB_FIRST,
+        })
+    }
+
+    /// Convert an opaque RGB color to a TrueColor pixel value.
+    fn pixel(&self, (r, g, b): (u8, u8, u8)) -> u32 {
+        scale_to_mask(r, self.red_mask) | scale_to_mask(g, self.green_mask) | scale_to_mask(b, self.blue_mask)
+    }
+
+    /// Split a TrueColor pixel value into 8-bit channels.
+    fn rgb(&self, value: u32) -> (u8, u8, u8) {
+        (
+            scale_from_mask(value, self.red_mask),
+            scale_from_mask(value, self.green_mask),
+            scale_from_mask(value, self.blue_mask),
+        )
+    }
+
+    /// Encode pixel values as ZPixmap rows. Returns the data and the row stride.
+    fn encode(&self, width: u16, values: &[u32]) -> (Vec<u8>, usize) {
+        let width = width as usize;
+        let bytes_per_pixel = self.bits_per_pixel as usize / 8;
+        let pad = self.scanline_pad as usize;
+        let stride = (width * self.bits_per_pixel as usize).div_ceil(pad) * pad / 8;
+        let mut data = vec![0u8; stride * values.len().div_ceil(width.max(1))];
+
+        for (i, &value) in values.iter().enumerate() {
+            let (y, x) = (i / width, i % width);
+            let offset = y * stride + x * bytes_per_pixel;
+            let out = &mut data[offset..offset + bytes_per_pixel];
+            for (n, byte) in out.iter_mut().enumerate() {
+                let shift = if self.lsb_first { n } else { bytes_per_pixel - 1 - n };
+                *byte = (value >> (shift * 8)) as u8;
+            }
+        }
+
+        (data, stride)
+    }
+
+    /// Decode ZPixmap rows into pixel values. Returns None if the data is short.
+    fn decode(&self, width: u16, height: u16, data: &[u8]) -> Option<Vec<u32>> {
+        let (width, height) = (width as usize, height as usize);
+        let bytes_per_pixel = self.bits_per_pixel as usize / 8;
+        let pad = self.scanline_pad as usize;
+        let stride = (width * self.bits_per_pixel as usize).div_ceil(pad) * pad / 8;
+        let depth_mask = if self.depth >= 32 { !0 } else { (1u32 << self.depth) - 1 };
+
+        if data.len() < stride * height {
+            return None;
+        }
+
+        let mut values = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let offset = y * stride + x * bytes_per_pixel;
+                let bytes = &data[offset..offset + bytes_per_pixel];
+                let value = if self.lsb_first {
+                    bytes.iter().rev().fold(0u32, |acc, &b| acc << 8 | b as u32)
+                } else {
+                    bytes.iter().fold(0u32, |acc, &b| acc << 8 | b as u32)
+                };
+                values.push(value & depth_mask);
+            }
+        }
+
+        Some(values)
+    }
+}
+
+/// Get pixel values for `levels` gray shades from black to white: computed
+/// from the masks on TrueColor, allocated in the default colormap otherwise.
+fn gray_palette(conn: &impl Connection, screen: &Screen, format: &PixelFormat, levels: u8) -> Option<Vec<u32>> {
+    let shade = |level: u8| (level as u32 * 255 / (levels as u32 - 1)) as u8;
+
+    if format.true_color {
+        return Some((0..levels).map(|l| format.pixel((shade(l), shade(l), shade(l)))).collect());
+    }
+
+    // Read-only shared cells: allocating the same shade again reuses the cell
+    (0..levels)
+        .map(|l| {
+            let v = shade(l) as u16 * 257;
+            let reply = conn.alloc_color(screen.default_colormap, v, v, v).ok()?.reply().ok()?;
+            Some(reply.pixel)
+        })
+        .collect()
+}
+
+/// Scale an 8-bit channel to the width of a visual's color mask.
+fn scale_to_mask(c: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let max = mask >> shift;
+    ((c as u32 * max + 127) / 255) << shift
+}
+
+/// Scale a visual's color channel back to 8 bits.
+fn scale_from_mask(value: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let max = mask >> shift;
+    (((value & mask) >> shift) * 255 / max) as u8
+}
+
+/// An icon uploaded to the server once, so redraws are a single copy.
+#[derive(Debug, Clone, Copy)]
+pub struct IconPixmap {
+    /// A depth-1 bitmap (1 = black, 0 = white) or a full-depth color pixmap.
+    pub pixmap: Pixmap,
+    /// Opaque area of a full-depth icon as a clip mask, if it has
+    /// transparent pixels, so the cell background shows through them.
+    pub mask: Option<Pixmap>,
+    pub depth: u8,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl IconPixmap {
+    /// Upload a B&W icon into a new bitmap on the screen of `drawable`.
+    pub fn upload_bitmap(
+        conn: &impl Connection,
+        drawable: Drawable,
+        icon: &BwIcon,
+    ) -> Result<IconPixmap, Box<dyn std::error::Error>> {
+        let pixmap = conn.generate_id()?;
+        let gc = conn.generate_id()?;
+
+        conn.create_pixmap(1, pixmap, drawable, icon.width, icon.height)?;
+        conn.create_gc(gc, pixmap, &CreateGCAux::new().foreground(1).background(0))?;
+        conn.put_image(
+            ImageFormat::XY_PIXMAP,
+            pixmap,
+            gc,
+            icon.width,
+            icon.height,
+            0,
+            0,
+            0,
+            1,
+            &bitmap_data(conn.setup(), icon),
+        )?;
+        conn.free_gc(gc)?;
+
+        Ok(IconPixmap {
+            pixmap,
+            mask: None,
+            depth: 1,
+            width: icon.width,
+            height: icon.height,
+        })
+    }
+
+    /// Upload the pixel values of `icon` into a new pixmap of the screen's
+    /// depth, with a mask of its opaque pixels. Large icons are sent in bands
+    /// of rows to stay within the request size limit.
+    fn upload_pixels(
+        conn: &impl Connection,
+        drawable: Drawable,
+        icon: &ArgbIcon,
+        values: &[u32],
+        format: &PixelFormat,
+    ) -> Result<IconPixmap, Box<dyn std::error::Error>> {
+        let pixmap = conn.generate_id()?;
+        let gc = conn.generate_id()?;
+        let (width, height) = (icon.width, icon.height);
+        let (data, stride) = format.encode(width, values);
+
+        conn.create_pixmap(format.depth, pixmap, drawable, width, height)?;
+        conn.create_gc(gc, pixmap, &CreateGCAux::new())?;
+
+        let rows_per_request = (conn.maximum_request_bytes().saturating_sub(64) / stride.max(1)).max(1);
+        for (band, rows) in data.chunks(rows_per_request * stride).enumerate() {
+            conn.put_image(
+                ImageFormat::Z_PIXMAP,
+                pixmap,
+                gc,
+                width,
+                (rows.len() / stride) as u16,
+                0,
+                (band * rows_per_request) as i16,
+                0,
+                format.depth,
+                rows,
+            )?;
+        }
+        conn.free_gc(gc)?;
+
+        let mask = match opacity_mask(icon) {
+            Some(bits) => Some(IconPixmap::upload_bitmap(conn, drawable, &bits)?.pixmap),
+            None => None,
+        };
+
+        Ok(IconPixmap {
+            pixmap,
+            mask,
+            depth: format.depth,
+            width,
+            height,
+        })
+    }
+
+    pub fn free(&self, conn: &impl Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.free_pixmap(self.pixmap)?;
+        if let Some(mask) = self.mask {
+            conn.free_pixmap(mask)?;
+        }
+        Ok(())
+    }
+}
+
+/// Pixels at least half opaque, as set bits of a clip mask. Returns None
+/// when the whole icon is opaque and needs no mask.
+fn opacity_mask(icon: &ArgbIcon) -> Option<BwIcon> {
+    let data: Vec<bool> = icon.pixels.iter().map(|&argb| argb >> 24 >= 0x80).collect();
+    if data.iter().all(|&opaque| opaque) {
+        return None;
+    }
+    Some(BwIcon {
+        width: icon.width,
+        height: icon.height,
+        data,
+    })
+}
+
+/// Pack a B&W icon into the server's bitmap format: rows padded to the
+/// scanline pad, bits in the server's bit order, and bytes swapped within
+/// each scanline unit when the byte order differs from the bit order.
+fn bitmap_data(setup: &Setup, icon: &BwIcon) -> Vec<u8> {
+    let pad = setup.bitmap_format_scanline_pad as usize;
+    let unit = setup.bitmap_format_scanline_unit as usize / 8;
+    let lsb_bits = setup.bitmap_format_bit_order == ImageOrder::LSB_FIRST;
+    let lsb_bytes = setup.image_byte_order == ImageOrder::LSB_FIRST;
+
+    let width = icon.width as usize;
+    let stride = width.div_ceil(pad) * pad / 8;
+    let mut data = vec![0u8; stride * icon.height as usize];
+
+    for (i, &black) in icon.data.iter().enumerate() {
+        if black {
+            let (y, x) = (i / width, i % width);
+            let bit = x % 8;
+            data[y * stride + x / 8] |= if lsb_bits { 1 << bit } else { 0x80 >> bit };
+        }
+    }
+
+    if lsb_bits != lsb_bytes && unit > 1 {
+        for chunk in data.chunks_mut(unit) {
+            chunk.reverse();
+        }
+    }
+
+    data
+}
+
+/// Largest WM_HINTS icon pixmap read, to avoid fetching huge images.
+const MAX_HINTS_ICON_SIZE: u16 = 512;
+
+/// Get a pixmap's width, height and depth.
+fn pixmap_geometry(conn: &impl Connection, pixmap: Pixmap) -> Option<(u16, u16, u8)> {
+    let geometry = conn.get_geometry(pixmap).ok()?.reply().ok()?;
+    let (width, height) = (geometry.width, geometry.height);
+
+    if width == 0 || height == 0 || width > MAX_HINTS_ICON_SIZE || height > MAX_HINTS_ICON_SIZE {
+        return None;
+    }
+    Some((width, height, geometry.depth))
+}
+
+/// Read a depth-1 pixmap as one bool per pixel, true for set bits.
+fn get_bitmap(conn: &impl Connection, pixmap: Pixmap, width: u16, height: u16) -> Option<Vec<bool>> {
+    let image = conn
+        .get_image(ImageFormat::XY_PIXMAP, pixmap, 0, 0, width, height, 1)
+        .ok()?
+        .reply()
+        .ok()?;
+    bitmap_bits(conn.setup(), width, height, image.data)
+}
+
+/// Inverse of `bitmap_data`: unpack XYBitmap data into one bool per pixel.
+fn bitmap_bits(setup: &Setup, width: u16, height: u16, mut data: Vec<u8>) -> Option<Vec<bool>> {
+    let pad = setup.bitmap_format_scanline_pad as usize;
+    let unit = setup.bitmap_format_scanline_unit as usize / 8;
+    let lsb_bits = setup.bitmap_format_bit_order == ImageOrder::LSB_FIRST;
+    let lsb_bytes = setup.image_byte_order == ImageOrder::LSB_FIRST;
+
+    let (width, height) = (width as usize, height as usize);
+    let stride = width.div_ceil(pad) * pad / 8;
+    if data.len() < stride * height {
+        return None;
+    }
+
+    if lsb_bits != lsb_bytes && unit > 1 {
+        for chunk in data.chunks_mut(unit) {
+            chunk.reverse();
+        }
+    }
+
+    let bits = (0..width * height)
+        .map(|i| {
+            let (y, x) = (i / width, i % width);
+            let byte = data[y * stride + x / 8];
+            let bit = x % 8;
+            byte & if lsb_bits { 1 << bit } else { 0x80 >> bit } != 0
+        })
+        .collect();
+    Some(bits)
+}
+
+/// Fetch _NET_WM_ICON, picking the entry closest to the target size.
+pub fn get_window_icon(conn: &impl Connection, window: Window, target_size: u16) -> Option<ArgbIcon> {
     let net_wm_icon = conn.intern_atom(false, b"_NET_WM_ICON").ok()?.reply().ok()?.atom;
 
     let reply = conn
@@ -54,12 +744,11 @@ pub fn get_window_icon(conn: &impl Connection, window: Window, target_size: u16)
     }
 
     let (width, height, pixels) = find_best_icon(data, target_size)?;
-    let bw = argb_to_bw(pixels);
 
-    let icon = BwIcon {
+    let icon = ArgbIcon {
         width: width as u16,
         height: height as u16,
-        data: bw,
+        pixels: pixels.to_vec(),
     };
 
     Some(icon.scale(target_size))
@@ -119,25 +808,91 @@ fn cast_bytes_to_u32(bytes: &[u8]) -> &[u32] {
     unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const u32, bytes.len() / 4) }
 }
 
-/// Convert ARGB pixels to B&W using luminance threshold.
-fn argb_to_bw(pixels: &[u32]) -> Vec<bool> {
-    pixels
-        .iter()
-        .map(|&argb| {
-            let a = ((argb >> 24) & 0xFF) as f32 / 255.0;
-            let r = ((argb >> 16) & 0xFF) as f32;
-            let g = ((argb >> 8) & 0xFF) as f32;
-            let b = (argb & 0xFF) as f32;
+/// Split an ARGB pixel into alpha and alpha-premultiplied color channels,
+/// so transparent pixels don't bleed their color into neighbors when scaling.
+fn premultiply(argb: u32) -> [f32; 4] {
+    let a = (argb >> 24) as f32 / 255.0;
+    [
+        a,
+        ((argb >> 16) & 0xff) as f32 * a,
+        ((argb >> 8) & 0xff) as f32 * a,
+        (argb & 0xff) as f32 * a,
+    ]
+}
 
-            // Luminance formula (ITU-R BT.601)
-            let lum = (0.299 * r + 0.587 * g + 0.114 * b) / 255.0;
-            // Blend with white background based on alpha
-            let value = lum * a + (1.0 - a);
+fn unpremultiply([a, r, g, b]: [f32; 4]) -> u32 {
+    if a <= 0.0 {
+        return 0;
+    }
+    let channel = |c: f32| (c / a).round().clamp(0.0, 255.0) as u32;
+    ((a * 255.0).round().clamp(0.0, 255.0) as u32) << 24 | channel(r) << 16 | channel(g) << 8 | channel(b)
+}
 
-            // Hard threshold - true = black
-            value < 0.5
-        })
-        .collect()
+/// Blend an ARGB pixel onto the white popup background.
+fn blend_on_white(argb: u32) -> (u8, u8, u8) {
+    let a = argb >> 24;
+    let blend = |c: u32| ((c * a + 255 * (255 - a)) / 255) as u8;
+    (blend((argb >> 16) & 0xff), blend((argb >> 8) & 0xff), blend(argb & 0xff))
+}
+
+/// Luminance of an ARGB pixel over white, from 0.0 (black) to 1.0 (white).
+fn luminance(argb: u32) -> f32 {
+    let a = ((argb >> 24) & 0xFF) as f32 / 255.0;
+    let r = ((argb >> 16) & 0xFF) as f32;
+    let g = ((argb >> 8) & 0xFF) as f32;
+    let b = (argb & 0xFF) as f32;
+
+    // Luminance formula (ITU-R BT.601)
+    let lum = (0.299 * r + 0.587 * g + 0.114 * b) / 255.0;
+    // Blend with white background based on alpha
+    lum * a + (1.0 - a)
+}
+
+/// Ordered dithering thresholds, in sixteenths.
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Reduce ARGB pixels to `levels` shades of gray, 0 being black and
+/// `levels - 1` white. With two levels and no dithering this is the classic
+/// hard threshold at 50% luminance.
+fn argb_to_levels(pixels: &[u32], width: usize, levels: u8, dither: Dither) -> Vec<u8> {
+    let max = (levels.max(2) - 1) as f32;
+    let quantize = |v: f32| v.round().clamp(0.0, max) as u8;
+    let mut values: Vec<f32> = pixels.iter().map(|&p| luminance(p) * max).collect();
+
+    match dither {
+        Dither::None => values.into_iter().map(quantize).collect(),
+        Dither::Bayer => values
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let t = (BAYER_4X4[(i / width) % 4][(i % width) % 4] as f32 + 0.5) / 16.0;
+                quantize((v + t).floor())
+            })
+            .collect(),
+        Dither::FloydSteinberg => {
+            let mut out = Vec::with_capacity(values.len());
+            for i in 0..values.len() {
+                let (y, x) = (i / width, i % width);
+                let level = quantize(values[i]);
+                let error = values[i] - level as f32;
+                out.push(level);
+
+                let mut spread = |dx: isize, dy: usize, weight: f32| {
+                    let nx = x as isize + dx;
+                    if nx >= 0 && (nx as usize) < width {
+                        if let Some(v) = values.get_mut((y + dy) * width + nx as usize) {
+                            *v += error * weight;
+                        }
+                    }
+                };
+                spread(1, 0, 7.0 / 16.0);
+                spread(-1, 1, 3.0 / 16.0);
+                spread(0, 1, 5.0 / 16.0);
+                spread(1, 1, 1.0 / 16.0);
+            }
+            out
+        }
+    }
 }
 
 /// Create a generic window icon (fallback when no icon available).
@@ -165,3 +920,152 @@ pub fn create_generic_icon(size: u16) -> BwIcon {
         data,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(v: u8) -> u32 {
+        0xff00_0000 | (v as u32) << 16 | (v as u32) << 8 | v as u32
+    }
+
+    /// A horizontal black-to-white gradient, the same on every row.
+    fn gradient(width: usize, height: usize) -> Vec<u32> {
+        (0..width * height)
+            .map(|i| gray(((i % width) * 255 / (width - 1)) as u8))
+            .collect()
+    }
+
+    fn white_fraction(levels: &[u8]) -> f32 {
+        levels.iter().filter(|&&l| l == 1).count() as f32 / levels.len() as f32
+    }
+
+    #[test]
+    fn threshold_splits_gradient_in_half() {
+        let levels = argb_to_levels(&gradient(16, 1), 16, 2, Dither::None);
+        assert_eq!(levels, [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn transparent_pixels_become_white() {
+        let pixels = vec![0x0000_0000; 16];
+        for dither in [Dither::None, Dither::FloydSteinberg, Dither::Bayer] {
+            assert!(argb_to_levels(&pixels, 4, 2, dither).iter().all(|&l| l == 1));
+        }
+    }
+
+    #[test]
+    fn floyd_steinberg_keeps_average_gray() {
+        let levels = argb_to_levels(&vec![gray(128); 32 * 32], 32, 2, Dither::FloydSteinberg);
+        let white = white_fraction(&levels);
+        assert!((0.45..=0.55).contains(&white), "white fraction {}", white);
+    }
+
+    #[test]
+    fn floyd_steinberg_follows_gradient() {
+        let (width, height) = (64, 16);
+        let levels = argb_to_levels(&gradient(width, height), width, 2, Dither::FloydSteinberg);
+
+        // Each quarter of the gradient is lighter than the one before
+        let quarter_white: Vec<usize> = (0..4)
+            .map(|q| {
+                levels
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, &l)| (i % width) / (width / 4) == q && l == 1)
+                    .count()
+            })
+            .collect();
+        assert!(quarter_white.windows(2).all(|w| w[0] < w[1]), "{:?}", quarter_white);
+    }
+
+    #[test]
+    fn bayer_quarter_gray_lights_a_quarter_of_the_pattern() {
+        let levels = argb_to_levels(&vec![gray(64); 8 * 8], 8, 2, Dither::Bayer);
+        assert_eq!(levels.iter().filter(|&&l| l == 1).count(), 16);
+    }
+
+    #[test]
+    fn bayer_keeps_black_and_white_solid() {
+        assert!(argb_to_levels(&[gray(0); 16], 4, 2, Dither::Bayer).iter().all(|&l| l == 0));
+        assert!(argb_to_levels(&[gray(255); 16], 4, 2, Dither::Bayer).iter().all(|&l| l == 1));
+    }
+
+    #[test]
+    fn gray_levels_cover_gradient_in_order() {
+        let levels = argb_to_levels(&gradient(256, 1), 256, 4, Dither::None);
+        assert_eq!(levels.first(), Some(&0));
+        assert_eq!(levels.last(), Some(&3));
+        assert!(levels.windows(2).all(|w| w[0] <= w[1]));
+        assert!((0..4).all(|l| levels.contains(&l)));
+    }
+
+    #[test]
+    fn dithered_gray_levels_stay_in_range() {
+        for dither in [Dither::FloydSteinberg, Dither::Bayer] {
+            let levels = argb_to_levels(&gradient(32, 8), 32, 5, dither);
+            assert!(levels.iter().all(|&l| l < 5));
+        }
+    }
+
+    #[test]
+    fn wide_icon_is_letterboxed_not_stretched() {
+        let icon = ArgbIcon { width: 64, height: 32, pixels: vec![gray(0); 64 * 32] };
+        let scaled = icon.scale(32);
+        assert_eq!((scaled.width, scaled.height), (32, 32));
+        let opaque_rows: Vec<usize> = (0..32).filter(|&y| scaled.pixel(16, y) >> 24 == 0xff).collect();
+        assert_eq!(opaque_rows, (8..24).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn tiny_icon_is_upscaled_by_whole_factor() {
+        let pixels = (0..16 * 16).map(|i| gray(if (i % 16 + i / 16) % 2 == 0 { 0 } else { 255 })).collect();
+        let scaled = ArgbIcon { width: 16, height: 16, pixels }.scale(48);
+        assert!(scaled.pixels.iter().all(|&p| p == gray(0) || p == gray(255)));
+        assert_eq!(scaled.pixel(2, 0), gray(0));
+        assert_eq!(scaled.pixel(3, 0), gray(255));
+    }
+
+    #[test]
+    fn box_filter_averages_and_ignores_transparent_color() {
+        let scaled = ArgbIcon { width: 2, height: 2, pixels: gradient(2, 2) }.scale_box(1, 1);
+        assert_eq!(scaled.pixels, [gray(128)]);
+
+        let half_clear = ArgbIcon { width: 2, height: 1, pixels: vec![0x00ff_0000, gray(128)] };
+        assert_eq!(half_clear.scale_box(1, 1).pixels, [0x8080_8080]);
+    }
+
+    #[test]
+    fn opacity_mask_covers_opaque_pixels_only() {
+        let icon = ArgbIcon { width: 2, height: 2, pixels: vec![gray(0), 0x7fff_ffff, 0x8000_0000, 0] };
+        let mask = opacity_mask(&icon).expect("icon has transparent pixels");
+        assert_eq!(mask.data, [true, false, true, false]);
+
+        let opaque = ArgbIcon { width: 2, height: 1, pixels: vec![gray(0), gray(255)] };
+        assert!(opacity_mask(&opaque).is_none());
+    }
+
+    #[test]
+    fn pixel_format_decodes_what_it_encodes() {
+        for (bits_per_pixel, lsb_first) in [(16, false), (32, true)] {
+            let format = PixelFormat {
+                depth: if bits_per_pixel == 16 { 16 } else { 24 },
+                bits_per_pixel,
+                scanline_pad: 32,
+                true_color: true,
+                red_mask: if bits_per_pixel == 16 { 0xf800 } else { 0xff0000 },
+                green_mask: if bits_per_pixel == 16 { 0x07e0 } else { 0x00ff00 },
+                blue_mask: if bits_per_pixel == 16 { 0x001f } else { 0x0000ff },
+                lsb_first,
+            };
+            let values: Vec<u32> = [(255, 0, 0), (0, 255, 0), (0, 0, 255), (255, 255, 255), (0, 0, 0), (255, 0, 255)]
+                .into_iter()
+                .map(|c| format.pixel(c))
+                .collect();
+            let (data, _) = format.encode(3, &values);
+            let decoded = format.decode(3, 2, &data).expect("data is long enough");
+            assert_eq!(decoded, values);
+            assert_eq!(format.rgb(values[5]), (255, 0, 255));
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//! Keysym names and keycode resolution via the server keyboard mapping.
+
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::*;
+
+/// An X11 keysym value.
+pub type Keysym = u32;
+
+pub const XK_BACKSPACE: Keysym = 0xff08;
+pub const XK_TAB: Keysym = 0xff09;
+pub const XK_RETURN: Keysym = 0xff0d;
+pub const XK_ESCAPE: Keysym = 0xff1b;
+pub const XK_ALT_L: Keysym = 0xffe9;
+pub const XK_ALT_R: Keysym = 0xffea;
+pub const XK_HOME: Keysym = 0xff50;
+pub const XK_LEFT: Keysym = 0xff51;
+pub const XK_UP: Keysym = 0xff52;
+pub const XK_RIGHT: Keysym = 0xff53;
+pub const XK_DOWN: Keysym = 0xff54;
+pub const XK_PAGE_UP: Keysym = 0xff55;
+pub const XK_PAGE_DOWN: Keysym = 0xff56;
+pub const XK_END: Keysym = 0xff57;
+pub const XK_NUM_LOCK: Keysym = 0xff7f;
+pub const XK_SCROLL_LOCK: Keysym = 0xff14;
+
+/// Named keysyms accepted in the config file. Single printable ASCII
+/// characters are accepted directly since their keysym equals the code point.
+const KEYSYM_NAMES: &[(&str, Keysym)] = &[
+    ("BackSpace", XK_BACKSPACE),
+    ("Tab", XK_TAB),
+    ("ISO_Left_Tab", 0xfe20),
+    ("Return", XK_RETURN),
+    ("Escape", XK_ESCAPE),
+    ("Delete", 0xffff),
+    ("Insert", 0xff63),
+    ("Home", XK_HOME),
+    ("Left", XK_LEFT),
+    ("Up", XK_UP),
+    ("Right", XK_RIGHT),
+    ("Down", XK_DOWN),
+    ("Prior", XK_PAGE_UP),
+    ("Page_Up", XK_PAGE_UP),
+    ("Next", XK_PAGE_DOWN),
+    ("Page_Down", XK_PAGE_DOWN),
+    ("End", XK_END),
+    ("F1", 0xffbe),
+    ("F2", 0xffbf),
+    ("F3", 0xffc0),
+    ("F4", 0xffc1),
+    ("F5", 0xffc2),
+    ("F6", 0xffc3),
+    ("F7", 0xffc4),
+    ("F8", 0xffc5),
+    ("F9", 0xffc6),
+    ("F10", 0xffc7),
+    ("F11", 0xffc8),
+    ("F12", 0xffc9),
+    ("Shift_L", 0xffe1),
+    ("Shift_R", 0xffe2),
+    ("Control_L", 0xffe3),
+    ("Control_R", 0xffe4),
+    ("Caps_Lock", 0xffe5),
+    ("Num_Lock", XK_NUM_LOCK),
+    ("Scroll_Lock", XK_SCROLL_LOCK),
+    ("Meta_L", 0xffe7),
+    ("Meta_R", 0xffe8),
+    ("Alt_L", XK_ALT_L),
+    ("Alt_R", XK_ALT_R),
+    ("Super_L", 0xffeb),
+    ("Super_R", 0xffec),
+    ("Hyper_L", 0xffed),
+    ("Hyper_R", 0xffee),
+    ("space", 0x0020),
+    ("exclam", 0x0021),
+    ("quotedbl", 0x0022),
+    ("numbersign", 0x0023),
+    ("dollar", 0x0024),
+    ("percent", 0x0025),
+    ("ampersand", 0x0026),
+    ("apostrophe", 0x0027),
+    ("parenleft", 0x0028),
+    ("parenright", 0x0029),
+    ("asterisk", 0x002a),
+    ("plus", 0x002b),
+    ("comma", 0x002c),
+    ("minus", 0x002d),
+    ("period", 0x002e),
+    ("slash", 0x002f),
+    ("colon", 0x003a),
+    ("semicolon", 0x003b),
+    ("less", 0x003c),
+    ("equal", 0x003d),
+    ("greater", 0x003e),
+    ("question", 0x003f),
+    ("at", 0x0040),
+    ("bracketleft", 0x005b),
+    ("backslash", 0x005c),
+    ("bracketright", 0x005d),
+    ("asciicircum", 0x005e),
+    ("underscore", 0x005f),
+    ("grave", 0x0060),
+    ("braceleft", 0x007b),
+    ("bar", 0x007c),
+    ("braceright", 0x007d),
+    ("asciitilde", 0x007e),
+];
+
+/// Look up a keysym by its X11 name (e.g. "Tab", "Alt_L", "grave", "a").
+pub fn keysym_from_name(name: &str) -> Option<Keysym> {
+    if let Some(&(_, sym)) = KEYSYM_NAMES.iter().find(|(n, _)| *n == name) {
+        return Some(sym);
+    }
+
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_graphic() => Some(c.to_ascii_lowercase() as Keysym),
+        _ => None,
+    }
+}
+
+/// Modifier bits that take part in hotkey matching: Shift, Control and
+/// Mod1-Mod5. Lock is left out so Caps Lock does not change which binding
+/// a key press selects.
+const MODIFIER_BITS: u16 = 0x00fd;
+
+/// What a global hotkey does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Open the switcher on the next window, release modifiers to activate.
+    Next,
+    /// Open the switcher on the previous window, release modifiers to activate.
+    Previous,
+    /// Like `Next`, but only among windows of the active window's class.
+    SameClass,
+    /// Open a switcher that stays up until Enter or Escape.
+    Persistent,
+}
+
+impl Action {
+    /// Name of the action as used in the `[hotkeys]` config section.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Next => "next",
+            Action::Previous => "previous",
+            Action::SameClass => "same_class",
+            Action::Persistent => "persistent",
+        }
+    }
+}
+
+/// A key combination such as `Alt+Shift+Tab`.
+#[derive(Debug, Clone)]
+pub struct Hotkey {
+    pub modifiers: ModMask,
+    pub keysym: Keysym,
+    /// The binding as written in the config, for messages.
+    pub name: String,
+}
+
+impl Hotkey {
+    /// Parse a `Mod+Mod+Key` binding string.
+    pub fn parse(s: &str) -> Result<Hotkey, String> {
+        let parts: Vec<&str> = s.split('+').map(str::trim).collect();
+        let (key, mods) = parts.split_last().ok_or_else(|| format!("empty hotkey `{}`", s))?;
+
+        let mut modifiers = ModMask::from(0u16);
+        for &m in mods {
+            modifiers |= modifier_from_name(m).ok_or_else(|| format!("unknown modifier `{}` in `{}`", m, s))?;
+        }
+
+        let keysym = keysym_from_name(key).ok_or_else(|| format!("unknown keysym `{}` in `{}`", key, s))?;
+
+        Ok(Hotkey {
+            modifiers,
+            keysym,
+            name: s.to_string(),
+        })
+    }
+
+    /// Check whether a key press with the given state triggers this hotkey.
+    pub fn matches(&self, keymap: &Keymap, keycode: Keycode, state: KeyButMask) -> bool {
+        let state = u16::from(keymap.clean_state(state));
+        keymap.is_keysym(keycode, self.keysym) && state & MODIFIER_BITS == u16::from(self.modifiers)
+    }
+}
+
+/// A hotkey bound to an action.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub hotkey: Hotkey,
+    pub action: Action,
+}
+
+fn modifier_from_name(name: &str) -> Option<ModMask> {
+    match name.to_ascii_lowercase().as_str() {
+        "shift" => Some(ModMask::SHIFT),
+        "ctrl" | "control" => Some(ModMask::CONTROL),
+        "alt" | "meta" | "mod1" => Some(ModMask::M1),
+        "mod2" => Some(ModMask::M2),
+        "mod3" => Some(ModMask::M3),
+        "super" | "win" | "mod4" => Some(ModMask::M4),
+        "mod5" => Some(ModMask::M5),
+        _ => None,
+    }
+}
+
+/// Snapshot of the server's keycode-to-keysym and modifier tables.
+pub struct Keymap {
+    min_keycode: Keycode,
+    keysyms_per_keycode: usize,
+    keysyms: Vec<Keysym>,
+    keycodes_per_modifier: usize,
+    modifier_keycodes: Vec<Keycode>,
+    /// Caps Lock plus whichever ModN bits Num Lock and Scroll Lock live on.
+    lock_mask: u16,
+}
+
+impl Keymap {
+    /// Fetch the keyboard mapping for the full keycode range.
+    pub fn fetch(conn: &impl Connection) -> Result<Keymap, Box<dyn std::error::Error>> {
+        let setup = conn.setup();
+        let min_keycode = setup.min_keycode;
+        let count = setup.max_keycode - min_keycode + 1;
+
+        let reply = conn.get_keyboard_mapping(min_keycode, count)?.reply()?;
+        let modmap = conn.get_modifier_mapping()?.reply()?;
+
+        log_fmt!(
+            "Keyboard mapping: keycodes {}..={}, {} keysyms per keycode",
+            min_keycode,
+            setup.max_keycode,
+            reply.keysyms_per_keycode
+        );
+
+        let mut keymap = Keymap {
+            min_keycode,
+            keysyms_per_keycode: reply.keysyms_per_keycode as usize,
+            keysyms: reply.keysyms,
+            keycodes_per_modifier: modmap.keycodes_per_modifier() as usize,
+            modifier_keycodes: modmap.keycodes,
+            lock_mask: u16::from(ModMask::LOCK),
+        };
+
+        let num_lock = keymap.keysym_modifier(XK_NUM_LOCK);
+        let scroll_lock = keymap.keysym_modifier(XK_SCROLL_LOCK);
+        keymap.lock_mask |= num_lock | scroll_lock;
+
+        log_fmt!("Lock modifiers: NumLock=0x{:x}, ScrollLock=0x{:x}", num_lock, scroll_lock);
+
+        Ok(keymap)
+    }
+
+    /// Get the modifier bits set by any key producing `keysym`.
+    fn keysym_modifier(&self, keysym: Keysym) -> u16 {
+        self.keycodes(keysym)
+            .into_iter()
+            .fold(0, |mask, keycode| mask | u16::from(self.modifier_mask(keycode)))
+    }
+
+    /// Every combination of lock modifiers, for grabbing a hotkey so that it
+    /// fires regardless of Caps Lock, Num Lock and Scroll Lock state.
+    pub fn lock_combinations(&self) -> Vec<ModMask> {
+        let bits: Vec<u16> = (0..8)
+            .map(|i| 1u16 << i)
+            .filter(|bit| self.lock_mask & bit != 0)
+            .collect();
+
+        (0..1u16 << bits.len())
+            .map(|combo| {
+                bits.iter()
+                    .enumerate()
+                    .filter(|(i, _)| combo & (1 << i) != 0)
+                    .fold(0, |mask, (_, bit)| mask | bit)
+            })
+            .map(ModMask::from)
+            .collect()
+    }
+
+    /// Strip lock modifiers from an event state.
+    pub fn clean_state(&self, state: KeyButMask) -> KeyButMask {
+        KeyButMask::from(u16::from(state) & !self.lock_mask)
+    }
+
+    /// Get the modifier mask a keycode contributes to (empty if none).
+    pub fn modifier_mask(&self, keycode: Keycode) -> ModMask {
+        if keycode == 0 || self.keycodes_per_modifier == 0 {
+            return ModMask::from(0u16);
+        }
+        self.modifier_keycodes
+            .chunks(self.keycodes_per_modifier)
+            .enumerate()
+            .filter(|(_, codes)| codes.contains(&keycode))
+            .fold(ModMask::from(0u16), |mask, (i, _)| mask | ModMask::from(1u16 << i))
+    }
+
+    /// All keysyms bound to a keycode, one per shift level.
+    fn keysyms_for(&self, keycode: Keycode) -> &[Keysym] {
+        if keycode < self.min_keycode || self.keysyms_per_keycode == 0 {
+            return &[];
+        }
+        let start = (keycode - self.min_keycode) as usize * self.keysyms_per_keycode;
+        self.keysyms
+            .get(start..start + self.keysyms_per_keycode)
+            .unwrap_or(&[])
+    }
+
+    /// Get the printable character a key press types, for the search query.
+    /// Shift picks the second keysym column; Control suppresses text so that
+    /// Ctrl combinations stay available as commands.
+    pub fn text_char(&self, keycode: Keycode, state: KeyButMask) -> Option<char> {
+        let state = u16::from(state);
+        if state & u16::from(KeyButMask::CONTROL) != 0 {
+            return None;
+        }
+
+        let syms = self.keysyms_for(keycode);
+        let shifted = state & u16::from(KeyButMask::SHIFT) != 0;
+        let keysym = match syms {
+            [_, upper, ..] if shifted && *upper != 0 => *upper,
+            [lower, ..] => *lower,
+            [] => return None,
+        };
+
+        // Latin-1 keysyms equal their code points
+        match keysym {
+            0x20..=0x7e | 0xa0..=0xff => char::from_u32(keysym),
+            _ => None,
+        }
+    }
+
+    /// Check whether a keycode produces the given keysym at any shift level.
+    pub fn is_keysym(&self, keycode: Keycode, keysym: Keysym) -> bool {
+        self.keysyms_for(keycode).contains(&keysym)
+    }
+
+    /// Find every keycode that produces the given keysym.
+    pub fn keycodes(&self, keysym: Keysym) -> Vec<Keycode> {
+        if self.keysyms_per_keycode == 0 {
+            return Vec::new();
+        }
+        self.keysyms
+            .chunks(self.keysyms_per_keycode)
+            .enumerate()
+            .filter(|(_, syms)| syms.contains(&keysym))
+            .map(|(i, _)| self.min_keycode + i as Keycode)
+            .collect()
+    }
+}
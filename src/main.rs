@@ -2,9 +2,13 @@
 
 use x11rb::connection::Connection;
 
-mod icons;
 #[macro_use]
 mod log;
+mod config;
+mod filter;
+mod history;
+mod icons;
+mod keys;
 mod switcher;
 mod ui;
 mod window;
@@ -18,15 +22,30 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         log::enable();
     }
 
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("xtabbie: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     let (conn, screen_num) = x11rb::connect(None)?;
     let screen = &conn.setup().roots[screen_num];
     let root = screen.root;
 
     log_fmt!("xtabbie started, test_mode={}, screen={}", test_mode, screen_num);
 
-    if test_mode {
-        switcher::run_test_mode(&conn, screen, root)
+    let result = if test_mode {
+        switcher::run_test_mode(&conn, screen, root, &config)
     } else {
-        switcher::run_daemon_mode(&conn, screen, root)
+        switcher::run_daemon_mode(&conn, screen, root, &config)
+    };
+
+    if let Err(e) = result {
+        eprintln!("xtabbie: {}", e);
+        std::process::exit(1);
     }
+
+    Ok(())
 }
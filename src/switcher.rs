@@ -1,49 +1,343 @@
 //! Window switcher creation and event handling.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use x11rb::connection::Connection;
+use x11rb::errors::ReplyError;
 use x11rb::protocol::xproto::*;
+use x11rb::protocol::randr::{self, ConnectionExt as _};
+use x11rb::protocol::ErrorKind;
 use x11rb::wrapper::ConnectionExt as _;
 use x11rb::COPY_DEPTH_FROM_PARENT;
 
-use crate::icons::{create_generic_icon, get_window_icon};
+use crate::config::{Config, LayoutConfig, LayoutStyle};
+use crate::filter::fuzzy_score;
+use crate::history::FocusHistory;
+use crate::icons::{IconCache, IconRenderer};
+use crate::keys::{
+    Action, Binding, Hotkey, Keymap, Keysym, XK_BACKSPACE, XK_DOWN, XK_END, XK_HOME, XK_LEFT, XK_PAGE_DOWN,
+    XK_PAGE_UP, XK_RIGHT, XK_UP,
+};
 use crate::log;
-use crate::ui::{draw_switcher, Layout, WindowInfo};
-use crate::window::{activate_window, collect_windows_by_zorder, log_window_debug_info, should_show_in_switcher};
-
-// X11 keycodes
-const TAB_KEYCODE: u8 = 23;
-const ALT_L_KEYCODE: u8 = 64;
-const ALT_R_KEYCODE: u8 = 108;
-const ESCAPE_KEYCODE: u8 = 9;
-const RETURN_KEYCODE: u8 = 36;
-
-// Layout constants
-const ICON_SIZE: u16 = 48;
-const PADDING: u16 = 8;
-const TITLE_HEIGHT: u16 = 24;
-const MAX_COLS: u16 = 20;
+use crate::ui::{draw_switcher, entry_label, Layout, WindowInfo, CAPTION_HEIGHT};
+use crate::window::{
+    activate_window, close_window, collect_windows_by_zorder, find_client_window, focus_window, get_wm_class, iconify_window, is_iconic,
+    is_viewable, kill_window, log_window_debug_info, should_show_in_switcher, Client,
+};
 
 /// Resources for a switcher window.
 struct SwitcherWindow {
+    /// Every window found, in MRU order.
+    all: Vec<WindowInfo>,
+    /// Entries shown in the grid: search matches, or every window (or
+    /// application group) when the query is empty.
     windows: Vec<WindowInfo>,
+    /// Typed search query; empty shows every window.
+    query: String,
+    /// Collapse windows into one entry per application.
+    grouped: bool,
+    /// Application whose group is currently expanded.
+    expanded: Option<String>,
     win_id: Window,
     gc_id: Gcontext,
     gc_inv_id: Gcontext,
+    /// Inverts black and white, for highlighting the selection.
+    gc_xor_id: Gcontext,
     layout: Layout,
+    /// Monitor area the popup is centered on, for re-layout when the list changes.
+    monitor: Rectangle,
 }
 
+impl SwitcherWindow {
+    /// Destroy the popup and free its GCs. Icon pixmaps belong to the cache.
+    fn destroy(&self, conn: &impl Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.free_gc(self.gc_id)?;
+        conn.free_gc(self.gc_inv_id)?;
+        conn.free_gc(self.gc_xor_id)?;
+        conn.destroy_window(self.win_id)?;
+        conn.flush()?;
+        Ok(())
+    }
+
+    fn redraw(&self, conn: &impl Connection, selected: usize) -> Result<(), Box<dyn std::error::Error>> {
+        draw_switcher(
+            conn,
+            self.win_id,
+            self.gc_id,
+            self.gc_inv_id,
+            self.gc_xor_id,
+            &self.windows,
+            selected,
+            &self.layout,
+            &self.query,
+        )
+    }
+
+    /// Recompute the layout for the current list and resize the popup in place.
+    fn relayout(
+        &mut self,
+        conn: &impl Connection,
+        config: &Config,
+        selected: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.layout = calculate_layout(&self.monitor, &self.windows, &config.layout);
+        conn.configure_window(
+            self.win_id,
+            &ConfigureWindowAux::new()
+                .x(self.layout.x as i32)
+                .y(self.layout.y as i32)
+                .width(self.layout.win_width as u32)
+                .height(self.layout.win_height as u32),
+        )?;
+        conn.clear_area(false, self.win_id, 0, 0, 0, 0)?;
+        self.redraw(conn, selected)
+    }
+
+    /// Windows from `all` matching `query`, best match first. Ties keep MRU order.
+    fn matching(&self, query: &str) -> Vec<WindowInfo> {
+        let mut scored: Vec<(i32, &WindowInfo)> = self
+            .all
+            .iter()
+            .filter_map(|w| {
+                let score = fuzzy_score(query, &w.title).max(fuzzy_score(query, &w.class))?;
+                Some((score, w))
+            })
+            .collect();
+        scored.sort_by_key(|&(score, _)| std::cmp::Reverse(score));
+        scored.into_iter().map(|(_, w)| w.clone()).collect()
+    }
+
+    /// Entries to show for a query and the current grouping state. Searching
+    /// always lists individual windows.
+    fn view(&self, query: &str) -> Vec<WindowInfo> {
+        if !query.is_empty() {
+            return self.matching(query);
+        }
+        match &self.expanded {
+            Some(class) => self.all.iter().filter(|w| &w.class == class).cloned().collect(),
+            None if self.grouped => group_by_class(&self.all),
+            None => self.all.clone(),
+        }
+    }
+
+    /// Check whether the entry at `index` is a collapsed application group.
+    fn is_group(&self, index: usize) -> bool {
+        self.windows.get(index).is_some_and(|w| w.count > 1)
+    }
+
+    /// The selected entry's client, unless the entry stands for a whole group.
+    fn single_client(&self, selected: usize) -> Option<Client> {
+        self.windows.get(selected).filter(|w| w.count == 1).map(|w| w.client)
+    }
+
+    /// Replace the selected group with its individual windows.
+    fn expand(
+        &mut self,
+        conn: &impl Connection,
+        config: &Config,
+        selected: &mut usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let class = self.windows[*selected].class.clone();
+        log_fmt!("Expanding group '{}'", class);
+        self.expanded = Some(class);
+        self.windows = self.view(&self.query);
+        *selected = 0;
+        self.relayout(conn, config, *selected)
+    }
+
+    /// Go back from an expanded group to the grouped view, selecting the group.
+    fn collapse(
+        &mut self,
+        conn: &impl Connection,
+        config: &Config,
+        selected: &mut usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(class) = self.expanded.take() else {
+            return Ok(());
+        };
+        log_fmt!("Collapsing group '{}'", class);
+        self.windows = self.view(&self.query);
+        *selected = self.windows.iter().position(|w| w.class == class).unwrap_or(0);
+        self.relayout(conn, config, *selected)
+    }
+
+    /// Change the search query and re-filter, selecting the best match.
+    /// A query matching nothing is refused with a bell and false is returned.
+    fn set_query(
+        &mut self,
+        conn: &impl Connection,
+        config: &Config,
+        query: String,
+        selected: &mut usize,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let windows = self.view(&query);
+        if windows.is_empty() {
+            conn.bell(0)?;
+            conn.flush()?;
+            return Ok(false);
+        }
+
+        log_fmt!("Search '{}': {} windows", query, windows.len());
+        self.windows = windows;
+        self.query = query;
+        *selected = 0;
+        self.relayout(conn, config, *selected)?;
+        Ok(true)
+    }
+
+    /// Show a window as iconified after asking the WM to iconify it.
+    fn mark_iconic(&mut self, client: Client) {
+        for w in self.all.iter_mut().chain(self.windows.iter_mut()) {
+            if w.client == client {
+                w.iconic = true;
+            }
+        }
+    }
+
+    /// Show the new icon of a client whose icon changed while the popup is open.
+    fn refresh_icon(
+        &mut self,
+        conn: &impl Connection,
+        icons: &mut IconCache,
+        client: Window,
+        selected: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.all.iter().any(|w| w.client.client == client) {
+            return Ok(());
+        }
+
+        let icon = icons.get(conn, client)?;
+        for w in self.all.iter_mut().chain(self.windows.iter_mut()) {
+            if w.client.client == client {
+                w.icon = icon;
+            }
+        }
+        self.redraw(conn, selected)
+    }
+
+    /// Drop the entry for a frame or client that went away, then resize the
+    /// popup in place. Returns false if that was the last window.
+    fn remove_window(
+        &mut self,
+        conn: &impl Connection,
+        config: &Config,
+        window: Window,
+        selected: &mut usize,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let gone = |w: &WindowInfo| w.client.frame == window || w.client.client == window;
+        if !self.all.iter().any(gone) {
+            return Ok(true);
+        }
+
+        log_fmt!("Window 0x{:x} went away, removing from switcher", window);
+        self.all.retain(|w| !gone(w));
+        if self.all.is_empty() {
+            return Ok(false);
+        }
+
+        let current = self.windows.get(*selected).map(|w| w.client);
+        self.windows = self.view(&self.query);
+
+        // Show everything again if the last match or group member went away
+        if self.windows.is_empty() {
+            self.query.clear();
+            self.expanded = None;
+            self.windows = self.view(&self.query);
+        }
+
+        // Keep the same entry selected, or the one that took its place
+        *selected = current
+            .and_then(|client| self.windows.iter().position(|w| w.client == client))
+            .unwrap_or((*selected).min(self.windows.len() - 1));
+
+        self.relayout(conn, config, *selected)?;
+        Ok(true)
+    }
+}
+
+/// Mouse buttons handled in the popup.
+const BUTTON_LEFT: u8 = 1;
+const BUTTON_MIDDLE: u8 = 2;
+const BUTTON_WHEEL_UP: u8 = 4;
+const BUTTON_WHEEL_DOWN: u8 = 5;
+
+/// How the switcher was opened, which decides how it is dismissed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// `--test`: Enter selects, no grabs are held.
+    Test,
+    /// Stays open until Enter selects or Escape cancels.
+    Persistent,
+    /// Releasing any modifier in `release` activates the selection.
+    Release {
+        release: ModMask,
+        /// Every modifier of the opening hotkey, Shift included, ignored
+        /// when matching the close, kill and iconify keys.
+        held: ModMask,
+    },
+}
+
+impl Mode {
+    /// Pick the mode for a hotkey binding. Bindings without a modifier to
+    /// release fall back to persistent behavior.
+    fn for_binding(binding: &Binding) -> Mode {
+        let release = u16::from(binding.hotkey.modifiers) & !u16::from(ModMask::SHIFT);
+        if binding.action == Action::Persistent || release == 0 {
+            Mode::Persistent
+        } else {
+            Mode::Release {
+                release: ModMask::from(release),
+                held: binding.hotkey.modifiers,
+            }
+        }
+    }
+}
+
+/// A cursor movement within the icon grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GridMove {
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+}
+
+/// Navigation keys handled while the switcher is open.
+const GRID_KEYS: &[(Keysym, GridMove)] = &[
+    (XK_LEFT, GridMove::Left),
+    (XK_RIGHT, GridMove::Right),
+    (XK_UP, GridMove::Up),
+    (XK_DOWN, GridMove::Down),
+    (XK_HOME, GridMove::Home),
+    (XK_END, GridMove::End),
+    (XK_PAGE_UP, GridMove::PageUp),
+    (XK_PAGE_DOWN, GridMove::PageDown),
+];
+
 /// Run the switcher in test mode (keyboard navigation, Enter to select).
 pub fn run_test_mode(
     conn: &impl Connection,
     screen: &Screen,
     root: Window,
+    config: &Config,
 ) -> Result<(), Box<dyn std::error::Error>> {
     log::clear();
     log_fmt!("=== Test mode started ===");
 
-    let switcher = create_switcher_window(conn, screen, root)?;
+    let mut keymap = Keymap::fetch(conn)?;
+    let mut history = FocusHistory::new(conn, root);
+    history.record_current(conn);
+
+    // Follow windows closing while the switcher is up
+    conn.change_window_attributes(root, &ChangeWindowAttributesAux::new().event_mask(EventMask::SUBSTRUCTURE_NOTIFY))?;
+
+    let renderer = IconRenderer::new(conn, screen, config.layout.cell_icon_size(), &config.icons);
+    let mut icons = IconCache::new(conn, renderer);
+    let mut switcher = create_switcher_window(conn, screen, root, config, &mut icons, &history, false)?;
 
     if switcher.windows.is_empty() {
         return Ok(());
@@ -51,100 +345,220 @@ pub fn run_test_mode(
 
     let mut selected: usize = 0;
 
+    let chosen = run_switcher_loop(
+        conn,
+        &mut switcher,
+        root,
+        config,
+        &mut keymap,
+        &mut history,
+        &mut icons,
+        Mode::Test,
+        config.keys.next,
+        &mut selected,
+    )?;
+
+    if let Some(client) = chosen {
+        if !activate_window(conn, &client, root)? {
+            log_fmt!("Exiting before 0x{:x} is restored, leaving focus to the WM", client.client);
+        }
+    }
+    Ok(())
+}
+
+/// Run the switcher in daemon mode (global hotkeys from the config).
+pub fn run_daemon_mode(
+    conn: &impl Connection,
+    screen: &Screen,
+    root: Window,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut keymap = Keymap::fetch(conn)?;
+    grab_hotkeys(conn, root, &keymap, config)?;
+
+    let mut history = FocusHistory::new(conn, root);
+    history.watch(conn)?;
+
+    // Icons are converted once and kept across popups; gray shades are
+    // allocated only once too
+    let renderer = IconRenderer::new(conn, screen, config.layout.cell_icon_size(), &config.icons);
+    let mut icons = IconCache::new(conn, renderer);
+
+    // A restored client waiting for the WM to map it before it is focused
+    let mut pending_focus: Option<Client> = None;
+
+    // Main daemon loop
     loop {
         let event = conn.wait_for_event()?;
+        history.handle_event(conn, &event);
+        icons.handle_event(conn, &event);
+
         match event {
-            x11rb::protocol::Event::Expose(_) => {
-                draw_switcher(
-                    conn,
-                    switcher.win_id,
-                    switcher.gc_id,
-                    switcher.gc_inv_id,
-                    &switcher.windows,
-                    selected,
-                    &switcher.layout,
-                )?;
-            }
-            x11rb::protocol::Event::KeyPress(ev) => match ev.detail {
-                TAB_KEYCODE => {
-                    selected = navigate_selection(selected, switcher.windows.len(), &ev);
-                    draw_switcher(
-                        conn,
-                        switcher.win_id,
-                        switcher.gc_id,
-                        switcher.gc_inv_id,
-                        &switcher.windows,
-                        selected,
-                        &switcher.layout,
-                    )?;
+            x11rb::protocol::Event::KeyPress(ev) => {
+                let binding = config
+                    .hotkeys
+                    .iter()
+                    .chain(&config.fallback_hotkeys)
+                    .find(|b| b.hotkey.matches(&keymap, ev.detail, ev.state));
+                if let Some(binding) = binding {
+                    pending_focus =
+                        show_switcher(conn, screen, root, config, &mut icons, &mut keymap, &mut history, binding)?;
                 }
-                RETURN_KEYCODE => {
-                    activate_window(conn, switcher.windows[selected].wid, root)?;
-                    break;
+            }
+            // The WM maps the frame and client of a restored window in
+            // either order; focus once both are up
+            x11rb::protocol::Event::MapNotify(ev) => {
+                if let Some(client) = pending_focus.filter(|c| ev.window == c.frame || ev.window == c.client) {
+                    if is_viewable(conn, client.client) {
+                        focus_window(conn, &client)?;
+                        pending_focus = None;
+                    }
                 }
-                ESCAPE_KEYCODE => break,
-                _ => {}
-            },
+            }
+            x11rb::protocol::Event::MappingNotify(ev) if ev.request != Mapping::POINTER => {
+                refresh_keymap(conn, &mut keymap)?;
+                grab_hotkeys(conn, root, &keymap, config)?;
+            }
             _ => {}
         }
     }
+}
+
+/// Grab every configured hotkey on the root window for the current keymap,
+/// once per combination of lock modifiers so Num Lock and friends don't matter.
+/// Any previous grabs are released first so this can be re-run on remapping.
+///
+/// If every binding for an action is already taken by another client, the
+/// action's `[fallback]` bindings are tried instead. Fails if no hotkey at
+/// all could be grabbed.
+fn grab_hotkeys(
+    conn: &impl Connection,
+    root: Window,
+    keymap: &Keymap,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    conn.ungrab_key(Grab::ANY, root, ModMask::ANY)?;
+
+    let mut grabbed = 0;
+
+    for action in [Action::Next, Action::Previous, Action::SameClass, Action::Persistent] {
+        let mut primary_count = 0;
+        let mut action_grabbed = 0;
+
+        for binding in config.hotkeys.iter().filter(|b| b.action == action) {
+            primary_count += 1;
+            if grab_binding(conn, root, keymap, binding)? {
+                action_grabbed += 1;
+            }
+        }
+
+        if primary_count > 0 && action_grabbed == 0 {
+            for binding in config.fallback_hotkeys.iter().filter(|b| b.action == action) {
+                if grab_binding(conn, root, keymap, binding)? {
+                    eprintln!("xtabbie: using fallback {} for {}", binding.hotkey.name, action.name());
+                    action_grabbed += 1;
+                }
+            }
+        }
+
+        grabbed += action_grabbed;
+    }
+
+    if grabbed == 0 {
+        return Err("no hotkey could be grabbed".into());
+    }
 
     Ok(())
 }
 
-/// Run the switcher in daemon mode (global Alt+Tab hotkey).
-pub fn run_daemon_mode(
+/// Grab one binding with checked requests so conflicts are noticed right away.
+/// Returns false (with nothing left grabbed) if another client owns any of
+/// the key combinations.
+fn grab_binding(
     conn: &impl Connection,
-    screen: &Screen,
     root: Window,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Grab Alt+Tab and Alt+Shift+Tab on root window
-    let mod_mask = ModMask::M1; // Alt
+    keymap: &Keymap,
+    binding: &Binding,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let keycodes = keymap.keycodes(binding.hotkey.keysym);
+    if keycodes.is_empty() {
+        eprintln!("xtabbie: no key produces {} in the current keymap", binding.hotkey.name);
+        return Ok(false);
+    }
 
-    conn.grab_key(true, root, mod_mask, TAB_KEYCODE, GrabMode::ASYNC, GrabMode::ASYNC)?;
-    conn.grab_key(
-        true,
-        root,
-        mod_mask | ModMask::SHIFT,
-        TAB_KEYCODE,
-        GrabMode::ASYNC,
-        GrabMode::ASYNC,
-    )?;
-    conn.flush()?;
+    let mut done = Vec::new();
 
-    // Main daemon loop
-    loop {
-        let event = conn.wait_for_event()?;
+    for keycode in keycodes {
+        log_fmt!(
+            "Grabbing {} (keycode {}) for {}",
+            binding.hotkey.name,
+            keycode,
+            binding.action.name()
+        );
+        for locks in keymap.lock_combinations() {
+            let modifiers = binding.hotkey.modifiers | locks;
+            let result = conn
+                .grab_key(true, root, modifiers, keycode, GrabMode::ASYNC, GrabMode::ASYNC)?
+                .check();
 
-        if let x11rb::protocol::Event::KeyPress(ev) = event {
-            if ev.detail == TAB_KEYCODE {
-                let shift_held = (ev.state & KeyButMask::SHIFT).bits() != 0;
-                show_switcher(conn, screen, root, shift_held)?;
+            match result {
+                Ok(()) => done.push((keycode, modifiers)),
+                Err(ReplyError::X11Error(e)) if e.error_kind == ErrorKind::Access => {
+                    eprintln!(
+                        "xtabbie: cannot grab {} for {}: already grabbed by another client",
+                        binding.hotkey.name,
+                        binding.action.name()
+                    );
+                    for (keycode, modifiers) in done {
+                        conn.ungrab_key(keycode, root, modifiers)?;
+                    }
+                    conn.flush()?;
+                    return Ok(false);
+                }
+                Err(e) => return Err(e.into()),
             }
         }
     }
+
+    Ok(true)
 }
 
-/// Show the switcher window and handle its event loop.
+/// Re-fetch the keyboard mapping after a keyboard or modifier MappingNotify.
+fn refresh_keymap(conn: &impl Connection, keymap: &mut Keymap) -> Result<(), Box<dyn std::error::Error>> {
+    log_fmt!("Keyboard mapping changed, reloading");
+    *keymap = Keymap::fetch(conn)?;
+    Ok(())
+}
+
+/// Show the switcher window for a hotkey, handle its event loop and activate
+/// the chosen window once the grabs are released. Returns the chosen client
+/// if it still has to be focused after the WM restores it.
+#[allow(clippy::too_many_arguments)]
 fn show_switcher(
     conn: &impl Connection,
     screen: &Screen,
     root: Window,
-    shift_held: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
+    config: &Config,
+    icons: &mut IconCache,
+    keymap: &mut Keymap,
+    history: &mut FocusHistory,
+    binding: &Binding,
+) -> Result<Option<Client>, Box<dyn std::error::Error>> {
     log::clear();
-    log_fmt!("=== Switcher activated (shift={}) ===", shift_held);
+    log_fmt!("=== Switcher activated ({} -> {}) ===", binding.hotkey.name, binding.action.name());
 
-    let switcher = create_switcher_window(conn, screen, root)?;
+    history.record_current(conn);
+    let same_class = binding.action == Action::SameClass;
+    let mut switcher = create_switcher_window(conn, screen, root, config, icons, history, same_class)?;
 
     if switcher.windows.is_empty() {
-        conn.destroy_window(switcher.win_id)?;
-        conn.flush()?;
-        return Ok(());
+        switcher.destroy(conn)?;
+        return Ok(None);
     }
 
-    // Start with second window selected (like traditional alt-tab), or last if shift
-    let mut selected = initial_selection(switcher.windows.len(), shift_held);
+    // Start with second window selected (like traditional alt-tab), or last if going backwards
+    let backwards = binding.action == Action::Previous;
+    let mut selected = initial_selection(switcher.windows.len(), backwards, config.behavior.select_previous);
 
     // Grab keyboard to get all key events while switcher is open
     conn.grab_keyboard(
@@ -154,59 +568,179 @@ fn show_switcher(
         GrabMode::ASYNC,
         GrabMode::ASYNC,
     )?;
+
+    // Grab the pointer so hover, clicks and the wheel reach the popup
+    conn.grab_pointer(
+        true,
+        switcher.win_id,
+        EventMask::POINTER_MOTION | EventMask::BUTTON_PRESS | EventMask::BUTTON_RELEASE,
+        GrabMode::ASYNC,
+        GrabMode::ASYNC,
+        x11rb::NONE,
+        x11rb::NONE,
+        x11rb::CURRENT_TIME,
+    )?;
     conn.flush()?;
 
-    let result = run_switcher_loop(conn, &switcher, root, &mut selected);
+    let mode = Mode::for_binding(binding);
+    let result = run_switcher_loop(
+        conn,
+        &mut switcher,
+        root,
+        config,
+        keymap,
+        history,
+        icons,
+        mode,
+        binding.hotkey.keysym,
+        &mut selected,
+    );
 
     // Cleanup
+    conn.ungrab_pointer(x11rb::CURRENT_TIME)?;
     conn.ungrab_keyboard(x11rb::CURRENT_TIME)?;
-    conn.destroy_window(switcher.win_id)?;
-    conn.flush()?;
+    switcher.destroy(conn)?;
 
-    result
+    match result? {
+        Some(client) if !activate_window(conn, &client, root)? => Ok(Some(client)),
+        _ => Ok(None),
+    }
 }
 
+/// Handle switcher events until a window is chosen, which is returned, or
+/// the switcher is cancelled.
+/// `cycle_key` is the key that opened the switcher; pressing it again cycles.
+/// Every event is also passed to `history` and `icons`, so focus tracking
+/// and icon invalidation carry on while the popup is open.
+#[allow(clippy::too_many_arguments)]
 fn run_switcher_loop(
     conn: &impl Connection,
-    switcher: &SwitcherWindow,
+    switcher: &mut SwitcherWindow,
     root: Window,
+    config: &Config,
+    keymap: &mut Keymap,
+    history: &mut FocusHistory,
+    icons: &mut IconCache,
+    mode: Mode,
+    cycle_key: Keysym,
     selected: &mut usize,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<Option<Client>, Box<dyn std::error::Error>> {
+    let keys = &config.keys;
+
     loop {
         let event = conn.wait_for_event()?;
+        history.handle_event(conn, &event);
+        if let Some(client) = icons.handle_event(conn, &event) {
+            switcher.refresh_icon(conn, icons, client, *selected)?;
+        }
+
         match event {
             x11rb::protocol::Event::Expose(_) => {
-                draw_switcher(
-                    conn,
-                    switcher.win_id,
-                    switcher.gc_id,
-                    switcher.gc_inv_id,
-                    &switcher.windows,
-                    *selected,
-                    &switcher.layout,
-                )?;
+                switcher.redraw(conn, *selected)?;
+            }
+            x11rb::protocol::Event::KeyPress(ev) => {
+                if keymap.is_keysym(ev.detail, cycle_key) || keymap.is_keysym(ev.detail, keys.next) {
+                    *selected = navigate_selection(*selected, switcher.windows.len(), keymap.clean_state(ev.state));
+                    switcher.redraw(conn, *selected)?;
+                } else if let Some(grid_move) = grid_move_for(keymap, ev.detail) {
+                    match grid_move {
+                        GridMove::Down if switcher.is_group(*selected) => switcher.expand(conn, config, selected)?,
+                        // Up from the top row leaves an expanded group
+                        GridMove::Up if switcher.expanded.is_some() && *selected < switcher.layout.cols as usize => {
+                            switcher.collapse(conn, config, selected)?
+                        }
+                        _ => {
+                            *selected = move_in_grid(*selected, switcher.windows.len(), &switcher.layout, grid_move);
+                            switcher.redraw(conn, *selected)?;
+                        }
+                    }
+                } else if keymap.is_keysym(ev.detail, keys.select) {
+                    if switcher.is_group(*selected) {
+                        switcher.expand(conn, config, selected)?;
+                    } else {
+                        return Ok(Some(switcher.windows[*selected].client));
+                    }
+                } else if keymap.is_keysym(ev.detail, keys.cancel) {
+                    return Ok(None);
+                } else if pressed(&keys.close, keymap, mode, ev.detail, ev.state) {
+                    if let Some(client) = switcher.single_client(*selected) {
+                        close_window(conn, &client)?;
+                    }
+                } else if pressed(&keys.kill, keymap, mode, ev.detail, ev.state) {
+                    if let Some(client) = switcher.single_client(*selected) {
+                        kill_window(conn, &client)?;
+                    }
+                } else if pressed(&keys.iconify, keymap, mode, ev.detail, ev.state) {
+                    if let Some(client) = switcher.single_client(*selected) {
+                        iconify_window(conn, &client, root)?;
+                        switcher.mark_iconic(client);
+                        switcher.redraw(conn, *selected)?;
+                    }
+                } else if keymap.is_keysym(ev.detail, XK_BACKSPACE) {
+                    if !switcher.query.is_empty() {
+                        let mut query = switcher.query.clone();
+                        query.pop();
+                        switcher.set_query(conn, config, query, selected)?;
+                    }
+                } else if let Some(c) = keymap.text_char(ev.detail, ev.state) {
+                    let query = format!("{}{}", switcher.query, c);
+                    switcher.set_query(conn, config, query, selected)?;
+                }
+            }
+            // Hotkey modifier released - activate and close
+            x11rb::protocol::Event::KeyRelease(ev) if released_modifier(keymap, mode, ev.detail) => {
+                return Ok(Some(switcher.windows[*selected].client));
             }
-            x11rb::protocol::Event::KeyPress(ev) => match ev.detail {
-                TAB_KEYCODE => {
-                    *selected = navigate_selection(*selected, switcher.windows.len(), &ev);
-                    draw_switcher(
-                        conn,
-                        switcher.win_id,
-                        switcher.gc_id,
-                        switcher.gc_inv_id,
-                        &switcher.windows,
-                        *selected,
-                        &switcher.layout,
-                    )?;
+            // Hover moves the selection
+            x11rb::protocol::Event::MotionNotify(ev) => {
+                if let Some(index) = window_at(switcher, *selected, ev.event_x, ev.event_y) {
+                    if index != *selected {
+                        *selected = index;
+                        switcher.redraw(conn, *selected)?;
+                    }
+                }
+            }
+            x11rb::protocol::Event::ButtonPress(ev) => match ev.detail {
+                BUTTON_LEFT => match window_at(switcher, *selected, ev.event_x, ev.event_y) {
+                    Some(index) if switcher.is_group(index) => {
+                        *selected = index;
+                        switcher.expand(conn, config, selected)?;
+                    }
+                    Some(index) => {
+                        return Ok(Some(switcher.windows[index].client));
+                    }
+                    // Clicking outside the popup dismisses it
+                    None if !inside_popup(&switcher.layout, ev.event_x, ev.event_y) => return Ok(None),
+                    None => {}
+                },
+                BUTTON_MIDDLE => {
+                    let target = window_at(switcher, *selected, ev.event_x, ev.event_y);
+                    if let Some(client) = target.and_then(|index| switcher.single_client(index)) {
+                        close_window(conn, &client)?;
+                    }
+                }
+                BUTTON_WHEEL_UP | BUTTON_WHEEL_DOWN => {
+                    let count = switcher.windows.len();
+                    *selected = if ev.detail == BUTTON_WHEEL_UP {
+                        (*selected + count - 1) % count
+                    } else {
+                        (*selected + 1) % count
+                    };
+                    switcher.redraw(conn, *selected)?;
                 }
-                ESCAPE_KEYCODE => return Ok(()),
                 _ => {}
             },
-            x11rb::protocol::Event::KeyRelease(ev) => {
-                // Alt released - activate and close
-                if ev.detail == ALT_L_KEYCODE || ev.detail == ALT_R_KEYCODE {
-                    activate_window(conn, switcher.windows[*selected].wid, root)?;
-                    return Ok(());
+            // A listed window was closed: refresh the grid in place
+            x11rb::protocol::Event::DestroyNotify(ev) => {
+                let remaining = switcher.remove_window(conn, config, ev.window, selected)?;
+                if !remaining {
+                    return Ok(None);
+                }
+            }
+            x11rb::protocol::Event::MappingNotify(ev) if ev.request != Mapping::POINTER => {
+                refresh_keymap(conn, keymap)?;
+                if mode != Mode::Test {
+                    grab_hotkeys(conn, root, keymap, config)?;
                 }
             }
             _ => {}
@@ -214,20 +748,55 @@ fn run_switcher_loop(
     }
 }
 
-fn initial_selection(window_count: usize, shift_held: bool) -> usize {
+/// Hit-test popup coordinates against the cells of the current page.
+fn window_at(switcher: &SwitcherWindow, selected: usize, x: i16, y: i16) -> Option<usize> {
+    let slot = switcher.layout.cell_at(x, y)?;
+    let index = switcher.layout.page_of(selected) * switcher.layout.per_page() + slot;
+    (index < switcher.windows.len()).then_some(index)
+}
+
+fn inside_popup(layout: &Layout, x: i16, y: i16) -> bool {
+    x >= 0 && y >= 0 && (x as u16) < layout.win_width && (y as u16) < layout.win_height
+}
+
+/// Check whether a key press triggers one of `hotkeys`. Modifiers of the
+/// hotkey that opened a release-mode switcher are ignored, so Delete works
+/// while Alt is down, and Shift held from Alt+Shift+Tab doesn't turn Delete
+/// into Shift+Delete.
+fn pressed(hotkeys: &[Hotkey], keymap: &Keymap, mode: Mode, keycode: Keycode, state: KeyButMask) -> bool {
+    let state = match mode {
+        Mode::Release { held, .. } => KeyButMask::from(u16::from(state) & !u16::from(held)),
+        Mode::Test | Mode::Persistent => state,
+    };
+    hotkeys.iter().any(|h| h.matches(keymap, keycode, state))
+}
+
+/// Check whether a released key is one of the modifiers holding the switcher open.
+fn released_modifier(keymap: &Keymap, mode: Mode, keycode: Keycode) -> bool {
+    match mode {
+        Mode::Release { release, .. } => u16::from(keymap.modifier_mask(keycode) & release) != 0,
+        Mode::Test | Mode::Persistent => false,
+    }
+}
+
+fn initial_selection(window_count: usize, backwards: bool, select_previous: bool) -> usize {
     if window_count > 1 {
-        if shift_held {
+        if backwards {
             window_count - 1
-        } else {
+        } else if select_previous {
             1
+        } else {
+            0
         }
     } else {
         0
     }
 }
 
-fn navigate_selection(current: usize, count: usize, ev: &KeyPressEvent) -> usize {
-    let shift_held = (ev.state & KeyButMask::SHIFT).bits() != 0;
+/// Move the selection one step, backwards if Shift is held.
+/// `state` must already have lock modifiers stripped.
+fn navigate_selection(current: usize, count: usize, state: KeyButMask) -> usize {
+    let shift_held = (state & KeyButMask::SHIFT).bits() != 0;
     if shift_held {
         if current == 0 {
             count - 1
@@ -239,77 +808,326 @@ fn navigate_selection(current: usize, count: usize, ev: &KeyPressEvent) -> usize
     }
 }
 
+fn grid_move_for(keymap: &Keymap, keycode: Keycode) -> Option<GridMove> {
+    GRID_KEYS
+        .iter()
+        .find(|&&(keysym, _)| keymap.is_keysym(keycode, keysym))
+        .map(|&(_, grid_move)| grid_move)
+}
+
+/// Apply a grid movement. Left/Right wrap around the whole list like Tab;
+/// Up/Down move by a row and stop at the edges, landing on the last window
+/// when the row below is short.
+fn move_in_grid(current: usize, count: usize, layout: &Layout, grid_move: GridMove) -> usize {
+    let cols = layout.cols as usize;
+    let last = count - 1;
+
+    match grid_move {
+        GridMove::Left => {
+            if current == 0 {
+                last
+            } else {
+                current - 1
+            }
+        }
+        GridMove::Right => (current + 1) % count,
+        GridMove::Up => current.checked_sub(cols).unwrap_or(current),
+        GridMove::Down if current / cols < last / cols => (current + cols).min(last),
+        GridMove::Down => current,
+        GridMove::Home => 0,
+        GridMove::End => last,
+        GridMove::PageUp => current.saturating_sub(layout.per_page()),
+        GridMove::PageDown => (current + layout.per_page()).min(last),
+    }
+}
+
 /// Create the switcher window with all discovered windows.
+/// With `same_class`, only windows sharing the focused window's WM_CLASS are kept.
 fn create_switcher_window(
     conn: &impl Connection,
     screen: &Screen,
     root: Window,
+    config: &Config,
+    icons: &mut IconCache,
+    history: &FocusHistory,
+    same_class: bool,
 ) -> Result<SwitcherWindow, Box<dyn std::error::Error>> {
     log_fmt!("Collecting windows...");
 
-    // Gather windows in Z-order (MRU - most recently used first)
+    // Gather windows in Z-order, then put them in focus-history (MRU) order
     let window_list = collect_windows_by_zorder(conn, root);
-    let windows = deduplicate_windows(conn, window_list, root);
+    let mut windows = deduplicate_windows(conn, icons, window_list)?;
+    sort_by_focus_history(history, &mut windows);
+
+    if same_class {
+        filter_same_class(conn, history, &mut windows);
+    }
+
+    let grouped = config.behavior.group_by_class && !same_class;
+    let shown = if grouped { group_by_class(&windows) } else { windows.clone() };
 
     // Calculate layout
-    let layout = calculate_layout(screen, windows.len());
+    let monitor = monitor_area(conn, screen);
+    let layout = calculate_layout(&monitor, &shown, &config.layout);
 
     // Create the window
-    let (win_id, gc_id, gc_inv_id) = create_x11_window(conn, screen, root, &layout)?;
+    let (win_id, gc_id, gc_inv_id, gc_xor_id) = create_x11_window(conn, screen, root, &layout)?;
 
     Ok(SwitcherWindow {
-        windows,
+        all: windows,
+        windows: shown,
+        query: String::new(),
+        grouped,
+        expanded: None,
         win_id,
         gc_id,
         gc_inv_id,
+        gc_xor_id,
         layout,
+        monitor,
     })
 }
 
-fn deduplicate_windows(conn: &impl Connection, window_list: Vec<(Window, String)>, root: Window) -> Vec<WindowInfo> {
-    let generic_icon = create_generic_icon(ICON_SIZE);
-    let mut seen_titles = HashSet::new();
+/// Filter and deduplicate the collected windows, taking each icon from the
+/// cache and rendering the ones not seen before.
+fn deduplicate_windows(
+    conn: &impl Connection,
+    icons: &mut IconCache,
+    window_list: Vec<(Client, String)>,
+) -> Result<Vec<WindowInfo>, Box<dyn std::error::Error>> {
+    let mut seen_clients = HashSet::new();
     let mut windows = Vec::new();
 
     log_fmt!("Found {} windows before filtering", window_list.len());
 
-    for (wid, title) in window_list {
-        log_window_debug_info(conn, wid, root);
+    for (client, title) in window_list {
+        log_window_debug_info(conn, &client);
 
         // Check EWMH filtering first
-        let (should_show, reason) = should_show_in_switcher(conn, wid);
+        let (should_show, reason) = should_show_in_switcher(conn, &client);
         if !should_show {
             log_fmt!("  -> FILTERED OUT ({})", reason);
             continue;
         }
 
-        // Then check for duplicate titles
-        if seen_titles.insert(title.clone()) {
-            log_fmt!("  -> INCLUDED (unique title)");
-            let icon = get_window_icon(conn, wid, ICON_SIZE)
-                .unwrap_or_else(|| generic_icon.scale(ICON_SIZE));
+        // Then drop repeated entries for the same client
+        if seen_clients.insert(client.client) {
+            log_fmt!("  -> INCLUDED");
+            let icon = icons.get(conn, client.client)?;
+
+            let iconic = is_iconic(conn, &client);
+            let class = get_wm_class(conn, client.client).map(|(_, c)| c).unwrap_or_default();
 
-            windows.push(WindowInfo { wid, title, icon });
+            windows.push(WindowInfo {
+                client,
+                title,
+                class,
+                icon,
+                iconic,
+                count: 1,
+            });
         } else {
-            log_fmt!("  -> SKIPPED (duplicate title)");
+            log_fmt!("  -> SKIPPED (duplicate client)");
         }
     }
 
+    disambiguate_titles(&mut windows);
+
     log_fmt!("Final window count: {}", windows.len());
-    windows
+    Ok(windows)
+}
+
+/// Append a counter to windows sharing a title, e.g. "bash", "bash (2)".
+/// Numbering follows client window IDs so it stays stable between invocations.
+fn disambiguate_titles(windows: &mut [WindowInfo]) {
+    let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, w) in windows.iter().enumerate() {
+        groups.entry(w.title.clone()).or_default().push(i);
+    }
+
+    for mut indices in groups.into_values().filter(|g| g.len() > 1) {
+        indices.sort_by_key(|&i| windows[i].client.client);
+        for (n, &i) in indices.iter().enumerate().skip(1) {
+            windows[i].title = format!("{} ({})", windows[i].title, n + 1);
+        }
+    }
 }
 
-fn calculate_layout(screen: &Screen, window_count: usize) -> Layout {
-    let max_width = (screen.width_in_pixels as f32 * 0.8) as u16;
-    let max_cols_by_width = ((max_width - PADDING) / (ICON_SIZE + PADDING)).max(1);
-    let cols = (window_count as u16).min(max_cols_by_width).min(MAX_COLS).max(1);
-    let win_width = cols * (ICON_SIZE + PADDING) + PADDING;
+/// Collapse windows sharing a WM_CLASS class into one entry, placed where the
+/// application's most recent window is. Windows without a class stay separate.
+fn group_by_class(windows: &[WindowInfo]) -> Vec<WindowInfo> {
+    let mut groups: Vec<WindowInfo> = Vec::new();
+
+    for w in windows {
+        match groups.iter_mut().find(|g| !w.class.is_empty() && g.class == w.class) {
+            Some(group) => {
+                group.count += 1;
+                group.iconic &= w.iconic;
+                group.title = group.class.clone();
+            }
+            None => groups.push(w.clone()),
+        }
+    }
+
+    log_fmt!("Grouped {} windows into {} entries", windows.len(), groups.len());
+    groups
+}
+
+/// Order windows by focus history. Windows never seen focused keep their
+/// stacking order and go after those that were.
+fn sort_by_focus_history(history: &FocusHistory, windows: &mut [WindowInfo]) {
+    windows.sort_by_key(|w| history.position(w.client.frame).unwrap_or(usize::MAX));
+}
+
+/// Keep only windows whose WM_CLASS class matches the focused window's.
+fn filter_same_class(conn: &impl Connection, history: &FocusHistory, windows: &mut Vec<WindowInfo>) {
+    let Some(class) = focused_class(conn, history, windows) else {
+        log_fmt!("Same-class filter: no focused window class, showing all windows");
+        return;
+    };
+
+    windows.retain(|w| w.class == class);
+    log_fmt!("Same-class filter '{}': {} windows", class, windows.len());
+}
+
+/// Class of the focused top-level. It is usually in the list already; if
+/// not (e.g. it is skipped from the switcher), its WM_CLASS is read directly.
+/// Without any focus history, the first window's class is used.
+fn focused_class(conn: &impl Connection, history: &FocusHistory, windows: &[WindowInfo]) -> Option<String> {
+    let class = match history.current() {
+        Some(frame) => match windows.iter().find(|w| w.client.frame == frame) {
+            Some(w) => w.class.clone(),
+            None => find_client_window(conn, frame)
+                .and_then(|client| get_wm_class(conn, client))
+                .map(|(_, c)| c)?,
+        },
+        None => windows.first()?.class.clone(),
+    };
+    (!class.is_empty()).then_some(class)
+}
+
+/// Area of the monitor under the pointer, from RandR 1.5 monitors. Falls back
+/// to the primary monitor, and to the whole X screen without RandR 1.5.
+fn monitor_area(conn: &impl Connection, screen: &Screen) -> Rectangle {
+    let whole_screen = Rectangle {
+        x: 0,
+        y: 0,
+        width: screen.width_in_pixels,
+        height: screen.height_in_pixels,
+    };
+    let monitors = randr_monitors(conn, screen.root).unwrap_or_default();
+    let pointer = conn.query_pointer(screen.root).ok().and_then(|c| c.reply().ok());
+
+    let under_pointer = pointer.and_then(|p| {
+        monitors.iter().find(|m| {
+            let (x, y) = (p.root_x as i32 - m.x as i32, p.root_y as i32 - m.y as i32);
+            (0..m.width as i32).contains(&x) && (0..m.height as i32).contains(&y)
+        })
+    });
+    let area = under_pointer.or(monitors.first()).copied().unwrap_or(whole_screen);
+
+    log_fmt!(
+        "Monitor: {}x{}+{}+{} ({} monitors)",
+        area.width,
+        area.height,
+        area.x,
+        area.y,
+        monitors.len()
+    );
+    area
+}
+
+/// Active RandR monitors, primary first. None if the server lacks RandR 1.5.
+fn randr_monitors(conn: &impl Connection, root: Window) -> Option<Vec<Rectangle>> {
+    conn.extension_information(randr::X11_EXTENSION_NAME).ok()??;
+    let version = conn.randr_query_version(1, 5).ok()?.reply().ok()?;
+    if (version.major_version, version.minor_version) < (1, 5) {
+        return None;
+    }
+
+    let mut monitors = conn.randr_get_monitors(root, true).ok()?.reply().ok()?.monitors;
+    monitors.sort_by_key(|m| !m.primary);
+    let areas = monitors
+        .iter()
+        .filter(|m| m.width > 0 && m.height > 0)
+        .map(|m| Rectangle {
+            x: m.x,
+            y: m.y,
+            width: m.width,
+            height: m.height,
+        })
+        .collect();
+    Some(areas)
+}
+
+/// Compute the grid or list and the popup geometry, centered on the monitor.
+/// The popup is capped at `max_width`/`max_height` of the monitor; rows that
+/// don't fit are left out of `visible_rows`.
+fn calculate_layout(monitor: &Rectangle, windows: &[WindowInfo], config: &LayoutConfig) -> Layout {
+    /// Narrowest list, so short titles don't make a cramped popup.
+    const MIN_LIST_WIDTH: u16 = 320;
+
+    let LayoutConfig { style, padding, title_height, max_cols, max_width, max_height, captions, .. } = *config;
+    let icon_size = config.cell_icon_size();
+    let caption_height = if captions && style == LayoutStyle::Grid { CAPTION_HEIGHT } else { 0 };
+    let window_count = windows.len() as u16;
+    let max_width = (monitor.width as f32 * max_width) as u16;
+
+    let (cols, cell_width) = match style {
+        LayoutStyle::Grid => {
+            let max_cols_by_width = (max_width.saturating_sub(padding) / (icon_size + padding)).max(1);
+            (window_count.min(max_cols_by_width).clamp(1, max_cols), icon_size)
+        }
+        LayoutStyle::List => {
+            // Wide enough for the longest title and class, at 7 pixels a character
+            let text_chars = windows
+                .iter()
+                .map(|w| entry_label(w).chars().count() + w.class.chars().count() + 4)
+                .max()
+                .unwrap_or(0);
+            let wanted = icon_size + padding + (text_chars as u16).saturating_mul(7);
+            let widest = max_width.saturating_sub(padding * 2).max(icon_size);
+            (1, wanted.max(MIN_LIST_WIDTH).min(widest))
+        }
+    };
+    let rows = window_count.div_ceil(cols).max(1);
+
+    let row_height = icon_size + caption_height + padding;
+    let max_height = (monitor.height as f32 * max_height) as u16;
+    let max_rows_by_height = (max_height.saturating_sub(padding + title_height) / row_height).max(1);
+    let visible_rows = rows.min(max_rows_by_height);
+
+    let win_width = cols * (cell_width + padding) + padding;
+    let win_height = visible_rows * row_height + padding + title_height;
+
+    let x = monitor.x + (monitor.width.saturating_sub(win_width) / 2) as i16;
+    let y = monitor.y + (monitor.height.saturating_sub(win_height) / 2) as i16;
+
+    log_fmt!(
+        "Layout: {:?} {}x{} ({} rows visible), {}x{}+{}+{}",
+        style,
+        cols,
+        rows,
+        visible_rows,
+        win_width,
+        win_height,
+        x,
+        y
+    );
 
     Layout {
+        style,
         cols,
-        icon_size: ICON_SIZE,
-        padding: PADDING,
+        visible_rows,
+        icon_size,
+        cell_width,
+        caption_height,
+        padding,
+        title_height,
+        x,
+        y,
         win_width,
+        win_height,
     }
 }
 
@@ -318,26 +1136,20 @@ fn create_x11_window(
     screen: &Screen,
     root: Window,
     layout: &Layout,
-) -> Result<(Window, Gcontext, Gcontext), Box<dyn std::error::Error>> {
-    let Layout { cols, icon_size, padding, win_width } = *layout;
-
-    let rows = 1u16.max(cols); // Ensure at least one row
-    let _ = rows; // Layout calculation happens in draw_switcher
-    let win_height = icon_size + padding * 2 + TITLE_HEIGHT;
-
-    let x = (screen.width_in_pixels.saturating_sub(win_width)) / 2;
-    let y = (screen.height_in_pixels.saturating_sub(win_height)) / 2;
+) -> Result<(Window, Gcontext, Gcontext, Gcontext), Box<dyn std::error::Error>> {
+    let Layout { x, y, win_width, win_height, .. } = *layout;
 
     let win_id = conn.generate_id()?;
     let gc_id = conn.generate_id()?;
     let gc_inv_id = conn.generate_id()?;
+    let gc_xor_id = conn.generate_id()?;
 
     conn.create_window(
         COPY_DEPTH_FROM_PARENT,
         win_id,
         root,
-        x as i16,
-        y as i16,
+        x,
+        y,
         win_width,
         win_height,
         2,
@@ -347,7 +1159,14 @@ fn create_x11_window(
             .background_pixel(screen.white_pixel)
             .border_pixel(screen.black_pixel)
             .override_redirect(1)
-            .event_mask(EventMask::EXPOSURE | EventMask::KEY_PRESS | EventMask::KEY_RELEASE),
+            .event_mask(
+                EventMask::EXPOSURE
+                    | EventMask::KEY_PRESS
+                    | EventMask::KEY_RELEASE
+                    | EventMask::POINTER_MOTION
+                    | EventMask::BUTTON_PRESS
+                    | EventMask::BUTTON_RELEASE,
+            ),
     )?;
 
     conn.create_gc(
@@ -366,6 +1185,15 @@ fn create_x11_window(
             .background(screen.black_pixel),
     )?;
 
+    // XOR with black ^ white swaps the two on any visual
+    conn.create_gc(
+        gc_xor_id,
+        win_id,
+        &CreateGCAux::new()
+            .function(GX::XOR)
+            .foreground(screen.black_pixel ^ screen.white_pixel),
+    )?;
+
     conn.change_property8(
         PropMode::REPLACE,
         win_id,
@@ -377,5 +1205,77 @@ fn create_x11_window(
     conn.map_window(win_id)?;
     conn.flush()?;
 
-    Ok((win_id, gc_id, gc_inv_id))
+    Ok((win_id, gc_id, gc_inv_id, gc_xor_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4-column grid showing two rows per page.
+    fn grid() -> Layout {
+        Layout {
+            style: LayoutStyle::Grid,
+            cols: 4,
+            visible_rows: 2,
+            icon_size: 48,
+            cell_width: 48,
+            caption_height: 0,
+            padding: 8,
+            title_height: 24,
+            x: 0,
+            y: 0,
+            win_width: 232,
+            win_height: 144,
+        }
+    }
+
+    #[test]
+    fn left_and_right_wrap_around_the_list() {
+        let layout = grid();
+        assert_eq!(move_in_grid(0, 10, &layout, GridMove::Left), 9);
+        assert_eq!(move_in_grid(9, 10, &layout, GridMove::Right), 0);
+        assert_eq!(move_in_grid(3, 10, &layout, GridMove::Right), 4);
+    }
+
+    #[test]
+    fn up_and_down_stop_at_the_edges() {
+        let layout = grid();
+        assert_eq!(move_in_grid(2, 10, &layout, GridMove::Up), 2);
+        assert_eq!(move_in_grid(5, 10, &layout, GridMove::Up), 1);
+        assert_eq!(move_in_grid(1, 10, &layout, GridMove::Down), 5);
+        assert_eq!(move_in_grid(8, 10, &layout, GridMove::Down), 8);
+    }
+
+    #[test]
+    fn down_into_a_short_last_row_lands_on_the_last_window() {
+        let layout = grid();
+        assert_eq!(move_in_grid(5, 10, &layout, GridMove::Down), 9);
+        assert_eq!(move_in_grid(7, 10, &layout, GridMove::Down), 9);
+    }
+
+    #[test]
+    fn home_and_end_jump_to_the_ends() {
+        let layout = grid();
+        assert_eq!(move_in_grid(6, 10, &layout, GridMove::Home), 0);
+        assert_eq!(move_in_grid(6, 10, &layout, GridMove::End), 9);
+    }
+
+    #[test]
+    fn page_moves_clamp_to_the_list() {
+        let layout = grid();
+        assert_eq!(move_in_grid(1, 20, &layout, GridMove::PageDown), 9);
+        assert_eq!(move_in_grid(9, 20, &layout, GridMove::PageUp), 1);
+        assert_eq!(move_in_grid(3, 10, &layout, GridMove::PageUp), 0);
+        assert_eq!(move_in_grid(3, 10, &layout, GridMove::PageDown), 9);
+        assert_eq!(move_in_grid(9, 10, &layout, GridMove::PageDown), 9);
+    }
+
+    #[test]
+    fn single_window_stays_selected() {
+        let layout = grid();
+        for grid_move in GRID_KEYS.iter().map(|&(_, m)| m) {
+            assert_eq!(move_in_grid(0, 1, &layout, grid_move), 0, "{:?}", grid_move);
+        }
+    }
 }
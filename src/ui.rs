@@ -3,139 +3,391 @@
 use x11rb::connection::Connection;
 use x11rb::protocol::xproto::*;
 
-use crate::icons::BwIcon;
+use crate::config::LayoutStyle;
+use crate::icons::IconPixmap;
+use crate::window::Client;
+
+/// Height reserved under each grid icon for its caption.
+pub const CAPTION_HEIGHT: u16 = 14;
 
 /// Information about a window displayed in the switcher.
+#[derive(Clone)]
 pub struct WindowInfo {
-    pub wid: Window,
+    pub client: Client,
     pub title: String,
-    pub icon: BwIcon,
+    /// WM_CLASS class name, empty if the client doesn't set one.
+    pub class: String,
+    pub icon: IconPixmap,
+    /// Client is iconified (minimized); drawn with a dotted frame.
+    pub iconic: bool,
+    /// Number of windows this entry stands for; more than one for a
+    /// collapsed application group, which gets a count badge.
+    pub count: usize,
 }
 
-/// Layout constants for the switcher UI.
+/// Grid or list layout and popup geometry for the switcher UI.
+/// A list is laid out as a grid with a single, wide column.
 pub struct Layout {
+    pub style: LayoutStyle,
     pub cols: u16,
+    /// Rows that fit in the popup.
+    pub visible_rows: u16,
     pub icon_size: u16,
+    /// Width of a cell: the icon size in a grid, the row width in a list.
+    pub cell_width: u16,
+    /// Space under each icon for a caption; 0 when captions are off.
+    pub caption_height: u16,
     pub padding: u16,
+    pub title_height: u16,
+    pub x: i16,
+    pub y: i16,
     pub win_width: u16,
+    pub win_height: u16,
+}
+
+impl Layout {
+    /// Height of a cell, including its caption.
+    pub fn cell_height(&self) -> u16 {
+        self.icon_size + self.caption_height
+    }
+
+    /// Height of the icon grid above the title bar.
+    pub fn grid_height(&self) -> u16 {
+        self.visible_rows * (self.cell_height() + self.padding) + self.padding
+    }
+
+    /// Number of cells shown at once.
+    pub fn per_page(&self) -> usize {
+        (self.cols * self.visible_rows) as usize
+    }
+
+    /// Page holding the window at `index`.
+    pub fn page_of(&self, index: usize) -> usize {
+        index / self.per_page()
+    }
+
+    /// Number of pages needed for `count` windows.
+    pub fn page_count(&self, count: usize) -> usize {
+        count.div_ceil(self.per_page()).max(1)
+    }
+
+    /// Find the cell slot on the current page under popup coordinates.
+    /// Each cell's hit area extends halfway into the surrounding padding.
+    pub fn cell_at(&self, x: i16, y: i16) -> Option<usize> {
+        let x = x - (self.padding / 2) as i16;
+        let y = y - (self.padding / 2) as i16;
+        if x < 0 || y < 0 {
+            return None;
+        }
+
+        let col = (x / (self.cell_width + self.padding) as i16) as u16;
+        let row = (y / (self.cell_height() + self.padding) as i16) as u16;
+        if col >= self.cols || row >= self.visible_rows {
+            return None;
+        }
+
+        Some((row * self.cols + col) as usize)
+    }
+
+    /// Top-left corner of the cell at `slot` on the current page.
+    pub fn cell_origin(&self, slot: usize) -> (i16, i16) {
+        let col = slot as u16 % self.cols;
+        let row = slot as u16 / self.cols;
+        let x = self.padding + col * (self.cell_width + self.padding);
+        let y = self.padding + row * (self.cell_height() + self.padding);
+        (x as i16, y as i16)
+    }
 }
 
 /// Draw a single icon cell, optionally with selection highlight.
+/// B&W icons are inverted by drawing the selection box with `gc_xor`; color
+/// icons keep their colors and are drawn on top of a black box instead.
+#[allow(clippy::too_many_arguments)]
 pub fn draw_icon(
     conn: &impl Connection,
     win_id: Window,
     gc: Gcontext,
-    gc_inv: Gcontext,
+    gc_xor: Gcontext,
     x: i16,
     y: i16,
     cell_size: u16,
-    icon: &BwIcon,
+    icon: &IconPixmap,
     selected: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     const ICON_PADDING: i16 = 2;
-    let icon_size = icon.width as i16;
 
-    let icon_x = x + (cell_size as i16 - icon_size) / 2;
-    let icon_y = y + (cell_size as i16 - icon_size) / 2;
+    let icon_x = x + (cell_size as i16 - icon.width as i16) / 2;
+    let icon_y = y + (cell_size as i16 - icon.height as i16) / 2;
 
-    // Draw selection box if selected
-    if selected {
-        let box_rect = Rectangle {
-            x: icon_x - ICON_PADDING,
-            y: icon_y - ICON_PADDING,
-            width: (icon_size + ICON_PADDING * 2) as u16,
-            height: (icon_size + ICON_PADDING * 2) as u16,
-        };
-        conn.poly_fill_rectangle(win_id, gc, &[box_rect])?;
-    }
-
-    // Collect pixels by color for batch drawing
-    let mut black_pixels = Vec::new();
-    let mut white_pixels = Vec::new();
-
-    for iy in 0..icon.height {
-        for ix in 0..icon.width {
-            let idx = (iy * icon.width + ix) as usize;
-            let is_black = icon.data.get(idx).copied().unwrap_or(false);
-
-            let rect = Rectangle {
-                x: icon_x + ix as i16,
-                y: icon_y + iy as i16,
-                width: 1,
-                height: 1,
-            };
-
-            if selected {
-                // Invert colors when selected
-                if is_black {
-                    white_pixels.push(rect);
-                }
-            } else if is_black {
-                black_pixels.push(rect);
-            }
+    let box_rect = Rectangle {
+        x: icon_x - ICON_PADDING,
+        y: icon_y - ICON_PADDING,
+        width: icon.width + ICON_PADDING as u16 * 2,
+        height: icon.height + ICON_PADDING as u16 * 2,
+    };
+
+    if icon.depth == 1 {
+        blit_icon(conn, win_id, gc, icon, icon_x, icon_y)?;
+        if selected {
+            conn.poly_fill_rectangle(win_id, gc_xor, &[box_rect])?;
+        }
+    } else {
+        if selected {
+            conn.poly_fill_rectangle(win_id, gc, &[box_rect])?;
         }
+        blit_icon(conn, win_id, gc, icon, icon_x, icon_y)?;
     }
 
-    if !black_pixels.is_empty() {
-        conn.poly_fill_rectangle(win_id, gc, &black_pixels)?;
-    }
-    if !white_pixels.is_empty() {
-        conn.poly_fill_rectangle(win_id, gc_inv, &white_pixels)?;
-    }
+    Ok(())
+}
 
+/// Copy an icon pixmap to the window. Bitmaps are drawn in the GC's
+/// foreground (1 bits) and background (0 bits) colors; color icons are
+/// clipped to their mask so transparent pixels leave the window untouched.
+fn blit_icon(
+    conn: &impl Connection,
+    win_id: Window,
+    gc: Gcontext,
+    icon: &IconPixmap,
+    x: i16,
+    y: i16,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if icon.depth == 1 {
+        conn.copy_plane(icon.pixmap, win_id, gc, 0, 0, x, y, icon.width, icon.height, 1)?;
+    } else if let Some(mask) = icon.mask {
+        conn.change_gc(gc, &ChangeGCAux::new().clip_mask(mask).clip_x_origin(x as i32).clip_y_origin(y as i32))?;
+        conn.copy_area(icon.pixmap, win_id, gc, 0, 0, x, y, icon.width, icon.height)?;
+        conn.change_gc(gc, &ChangeGCAux::new().clip_mask(x11rb::NONE))?;
+    } else {
+        conn.copy_area(icon.pixmap, win_id, gc, 0, 0, x, y, icon.width, icon.height)?;
+    }
     Ok(())
 }
 
-/// Draw the complete switcher UI with all windows and selection.
+/// Draw the complete switcher UI. Only the page holding the selection is
+/// shown, so the selected cell is always visible. A non-empty `query` is
+/// shown in the title bar.
+#[allow(clippy::too_many_arguments)]
 pub fn draw_switcher(
     conn: &impl Connection,
     win_id: Window,
     gc_id: Gcontext,
     gc_inv_id: Gcontext,
+    gc_xor_id: Gcontext,
     windows: &[WindowInfo],
     selected: usize,
     layout: &Layout,
+    query: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let Layout { cols, icon_size, padding, .. } = *layout;
-
-    // Draw each window icon
-    for (i, winfo) in windows.iter().enumerate() {
-        let col = (i as u16) % cols;
-        let row = (i as u16) / cols;
+    let Layout { icon_size, cell_width, padding, .. } = *layout;
+    let per_page = layout.per_page();
+    let first = layout.page_of(selected) * per_page;
 
-        let cx = padding + col * (icon_size + padding);
-        let cy = padding + row * (icon_size + padding);
+    for slot in 0..per_page {
+        let i = first + slot;
+        let (cx, cy) = layout.cell_origin(slot);
 
-        // Clear cell background
+        // Clear cell background, including the margin used by the
+        // selection box and the iconified marker
+        let margin = padding / 2;
         let cell = Rectangle {
-            x: cx as i16,
-            y: cy as i16,
-            width: icon_size,
-            height: icon_size,
+            x: cx - margin as i16,
+            y: cy - margin as i16,
+            width: cell_width + margin * 2,
+            height: layout.cell_height() + margin * 2,
         };
         conn.poly_fill_rectangle(win_id, gc_inv_id, &[cell])?;
 
+        // Slots past the last window stay empty on the final page
+        let Some(winfo) = windows.get(i) else {
+            continue;
+        };
+
+        if layout.style == LayoutStyle::List {
+            draw_list_row(conn, win_id, gc_id, gc_xor_id, cx, cy, layout, winfo, i == selected)?;
+            continue;
+        }
+
         // Draw icon
         draw_icon(
             conn,
             win_id,
             gc_id,
-            gc_inv_id,
-            cx as i16,
-            cy as i16,
+            gc_xor_id,
+            cx,
+            cy,
             icon_size,
             &winfo.icon,
             i == selected,
         )?;
+
+        if winfo.iconic {
+            draw_iconic_marker(conn, win_id, gc_id, cx, cy, icon_size)?;
+        }
+
+        if winfo.count > 1 {
+            draw_count_badge(conn, win_id, gc_id, gc_inv_id, cx, cy, icon_size, winfo.count)?;
+        }
+
+        if layout.caption_height > 0 {
+            let caption_gc = if i == selected { gc_inv_id } else { gc_id };
+            draw_caption(conn, win_id, caption_gc, cx, cy, layout, &winfo.title)?;
+        }
     }
 
     // Draw title bar
-    draw_title_bar(conn, win_id, gc_id, gc_inv_id, windows, selected, layout)?;
+    draw_title_bar(conn, win_id, gc_id, gc_inv_id, windows, selected, layout, query)?;
 
     conn.flush()?;
     Ok(())
 }
 
+/// Draw one row of the list layout: icon, title and class, with the whole
+/// row inverted when selected.
+#[allow(clippy::too_many_arguments)]
+fn draw_list_row(
+    conn: &impl Connection,
+    win_id: Window,
+    gc: Gcontext,
+    gc_xor: Gcontext,
+    x: i16,
+    y: i16,
+    layout: &Layout,
+    winfo: &WindowInfo,
+    selected: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Layout { icon_size, cell_width, padding, .. } = *layout;
+
+    // B&W icons are inverted along with the row, color icons go on top
+    let bw_icon = winfo.icon.depth == 1;
+    if bw_icon {
+        draw_icon(conn, win_id, gc, gc_xor, x, y, icon_size, &winfo.icon, false)?;
+    }
+
+    let text_x = x + (icon_size + padding) as i16;
+    let text_y = y + icon_size as i16 / 2 + 5;
+    let text_area = cell_width.saturating_sub(icon_size + padding);
+
+    // Class goes on the right, the title gets the remaining space
+    let class = format!("({})", winfo.class);
+    let mut class_width = class.len() as u16 * 6;
+    if winfo.class.is_empty() || class_width * 2 > text_area {
+        class_width = 0;
+    } else {
+        let class_x = x + cell_width as i16 - class_width as i16;
+        draw_text(conn, win_id, gc, class_x, text_y, &class)?;
+    }
+
+    let title = truncate_title(&entry_label(winfo), text_area.saturating_sub(class_width + padding));
+    draw_text(conn, win_id, gc, text_x, text_y, &title)?;
+
+    // Invert the finished row: white on black
+    if selected {
+        let row = Rectangle {
+            x: x - (padding / 2) as i16,
+            y: y - (padding / 2) as i16,
+            width: cell_width + padding,
+            height: icon_size + padding,
+        };
+        conn.poly_fill_rectangle(win_id, gc_xor, &[row])?;
+    }
+
+    if !bw_icon {
+        draw_icon(conn, win_id, gc, gc_xor, x, y, icon_size, &winfo.icon, false)?;
+    }
+
+    Ok(())
+}
+
+/// Title of an entry with its group size or minimized state.
+pub fn entry_label(winfo: &WindowInfo) -> String {
+    if winfo.count > 1 {
+        format!("{} ({} windows)", winfo.title, winfo.count)
+    } else if winfo.iconic {
+        format!("{} (minimized)", winfo.title)
+    } else {
+        winfo.title.clone()
+    }
+}
+
+/// Draw a dotted frame around a cell to mark an iconified window.
+fn draw_iconic_marker(
+    conn: &impl Connection,
+    win_id: Window,
+    gc: Gcontext,
+    x: i16,
+    y: i16,
+    cell_size: u16,
+) -> Result<(), Box<dyn std::error::Error>> {
+    const OFFSET: i16 = 3;
+    let left = x - OFFSET;
+    let top = y - OFFSET;
+    let right = x + cell_size as i16 + OFFSET - 1;
+    let bottom = y + cell_size as i16 + OFFSET - 1;
+
+    let mut points = Vec::new();
+    for px in (left..=right).step_by(2) {
+        points.push(Point { x: px, y: top });
+        points.push(Point { x: px, y: bottom });
+    }
+    for py in (top..=bottom).step_by(2) {
+        points.push(Point { x: left, y: py });
+        points.push(Point { x: right, y: py });
+    }
+
+    conn.poly_point(CoordMode::ORIGIN, win_id, gc, &points)?;
+    Ok(())
+}
+
+/// Draw an ellipsized caption centered under a grid icon. The caption may
+/// use the padding on either side of the cell.
+fn draw_caption(
+    conn: &impl Connection,
+    win_id: Window,
+    gc: Gcontext,
+    x: i16,
+    y: i16,
+    layout: &Layout,
+    text: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let caption = truncate_title(text, layout.icon_size + layout.padding);
+    let text_width = caption.len() as i16 * 6;
+    let text_x = x + (layout.icon_size as i16 - text_width) / 2;
+    let text_y = y + layout.icon_size as i16 + 11;
+
+    draw_text(conn, win_id, gc, text_x, text_y, &caption)?;
+    Ok(())
+}
+
+/// Draw a boxed window count in the bottom-right corner of a group's cell.
+#[allow(clippy::too_many_arguments)]
+fn draw_count_badge(
+    conn: &impl Connection,
+    win_id: Window,
+    gc: Gcontext,
+    gc_inv: Gcontext,
+    x: i16,
+    y: i16,
+    cell_size: u16,
+    count: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    const BADGE_HEIGHT: u16 = 13;
+    let text = count.to_string();
+    let width = text.len() as u16 * 6 + 4;
+
+    let badge = Rectangle {
+        x: x + cell_size as i16 - width as i16,
+        y: y + cell_size as i16 - BADGE_HEIGHT as i16,
+        width,
+        height: BADGE_HEIGHT,
+    };
+    conn.poly_fill_rectangle(win_id, gc_inv, &[badge])?;
+    conn.poly_rectangle(win_id, gc, &[badge])?;
+    draw_text(conn, win_id, gc, badge.x + 2, badge.y + 10, &text)?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 fn draw_title_bar(
     conn: &impl Connection,
     win_id: Window,
@@ -144,20 +396,18 @@ fn draw_title_bar(
     windows: &[WindowInfo],
     selected: usize,
     layout: &Layout,
+    query: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    const TITLE_HEIGHT: u16 = 24;
-    let Layout { cols, icon_size, padding, win_width } = *layout;
+    let Layout { title_height, win_width, .. } = *layout;
 
-    let rows = ((windows.len() as u16 + cols - 1) / cols).max(1);
-    let icons_height = rows * (icon_size + padding) + padding;
-    let title_y = icons_height as i16;
+    let title_y = layout.grid_height() as i16;
 
     // Clear title background
     let title_bg = Rectangle {
         x: 0,
         y: title_y,
         width: win_width,
-        height: TITLE_HEIGHT,
+        height: title_height,
     };
     conn.poly_fill_rectangle(win_id, gc_inv_id, &[title_bg])?;
 
@@ -172,26 +422,134 @@ fn draw_title_bar(
         ],
     )?;
 
+    // Draw page marker on the right when windows span several pages
+    let pages = layout.page_count(windows.len());
+    let mut text_area = win_width;
+    if pages > 1 {
+        let marker = format!("{}/{}", layout.page_of(selected) + 1, pages);
+        let marker_width = marker.len() as u16 * 6 + 8;
+        let marker_x = win_width.saturating_sub(marker_width) as i16;
+        draw_text(conn, win_id, gc_id, marker_x, title_y + 16, &marker)?;
+        text_area = text_area.saturating_sub(marker_width * 2);
+    }
+
     // Draw title text
     if selected < windows.len() {
-        let title = &windows[selected].title;
-        let display_title = truncate_title(title, win_width);
+        let winfo = &windows[selected];
+        let mut title = entry_label(winfo);
+        if !query.is_empty() {
+            title = format!("[{}] {}", query, title);
+        }
+        let display_title = truncate_title(&title, text_area);
 
         let text_width = display_title.len() as i16 * 6;
         let text_x = ((win_width as i16) - text_width) / 2;
         let text_y = title_y + 16;
 
-        conn.image_text8(win_id, gc_id, text_x.max(4), text_y, display_title.as_bytes())?;
+        draw_text(conn, win_id, gc_id, text_x.max(4), text_y, &display_title)?;
     }
 
     Ok(())
 }
 
+/// Longest string a single ImageText8 request can carry.
+const MAX_TEXT_BYTES: usize = 255;
+
+/// Draw text with ImageText8, cut at a character boundary to the 255 bytes
+/// the request allows, since multi-byte titles can be longer than that.
+fn draw_text(
+    conn: &impl Connection,
+    win_id: Window,
+    gc: Gcontext,
+    x: i16,
+    y: i16,
+    text: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut end = text.len().min(MAX_TEXT_BYTES);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    conn.image_text8(win_id, gc, x, y, &text.as_bytes()[..end])?;
+    Ok(())
+}
+
 fn truncate_title(title: &str, win_width: u16) -> String {
     let max_chars = (win_width / 7) as usize;
-    if title.len() > max_chars {
-        format!("{}...", &title[..max_chars.saturating_sub(3)])
+    if title.chars().count() > max_chars {
+        let truncated: String = title.chars().take(max_chars.saturating_sub(3)).collect();
+        format!("{}...", truncated)
     } else {
         title.to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4-column grid of 48-pixel icons, two rows per page, 8 pixels apart.
+    fn grid(caption_height: u16) -> Layout {
+        Layout {
+            style: LayoutStyle::Grid,
+            cols: 4,
+            visible_rows: 2,
+            icon_size: 48,
+            cell_width: 48,
+            caption_height,
+            padding: 8,
+            title_height: 24,
+            x: 0,
+            y: 0,
+            win_width: 232,
+            win_height: 144 + caption_height * 2,
+        }
+    }
+
+    #[test]
+    fn cells_are_laid_out_after_the_padding() {
+        let layout = grid(0);
+        assert_eq!(layout.cell_origin(0), (8, 8));
+        assert_eq!(layout.cell_origin(3), (176, 8));
+        assert_eq!(layout.cell_origin(5), (64, 64));
+    }
+
+    #[test]
+    fn captions_make_rows_taller() {
+        let layout = grid(CAPTION_HEIGHT);
+        assert_eq!(layout.cell_height(), 62);
+        assert_eq!(layout.cell_origin(4), (8, 78));
+        assert_eq!(layout.cell_at(8, 78), Some(4));
+    }
+
+    #[test]
+    fn hit_area_splits_the_padding_between_cells() {
+        let layout = grid(0);
+        // Cell 0 spans 8..56; the gap up to cell 1 at 64 is split at 60
+        assert_eq!(layout.cell_at(4, 4), Some(0));
+        assert_eq!(layout.cell_at(59, 10), Some(0));
+        assert_eq!(layout.cell_at(60, 10), Some(1));
+        assert_eq!(layout.cell_at(10, 59), Some(0));
+        assert_eq!(layout.cell_at(10, 60), Some(4));
+    }
+
+    #[test]
+    fn points_outside_the_grid_hit_nothing() {
+        let layout = grid(0);
+        assert_eq!(layout.cell_at(3, 10), None);
+        assert_eq!(layout.cell_at(10, 3), None);
+        assert_eq!(layout.cell_at(-5, 10), None);
+        assert_eq!(layout.cell_at(228, 10), None);
+        assert_eq!(layout.cell_at(10, 116), None);
+    }
+
+    #[test]
+    fn pages_hold_the_visible_cells() {
+        let layout = grid(0);
+        assert_eq!(layout.per_page(), 8);
+        assert_eq!(layout.page_of(7), 0);
+        assert_eq!(layout.page_of(8), 1);
+        assert_eq!(layout.page_count(0), 1);
+        assert_eq!(layout.page_count(8), 1);
+        assert_eq!(layout.page_count(9), 2);
+    }
+}
@@ -5,6 +5,25 @@ use x11rb::protocol::xproto::*;
 
 use crate::log;
 
+/// A managed window: the top-level frame (direct child of root, which is the
+/// client itself under WMs that don't reparent) and the client window inside
+/// it that carries WM_STATE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Client {
+    pub frame: Window,
+    pub client: Window,
+}
+
+impl Client {
+    /// Build the pair for a known client window by walking up to its frame.
+    pub fn from_client(conn: &impl Connection, client: Window, root: Window) -> Client {
+        Client {
+            frame: find_toplevel_parent(conn, client, root),
+            client,
+        }
+    }
+}
+
 /// Get the title of an X11 window, trying _NET_WM_NAME first, then WM_NAME.
 pub fn get_window_title(conn: &impl Connection, window: Window) -> Option<String> {
     // Try _NET_WM_NAME (UTF-8) first
@@ -74,6 +93,41 @@ pub fn is_viewable(conn: &impl Connection, window: Window) -> bool {
         .unwrap_or(false)
 }
 
+/// ICCCM WM_STATE state field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WmState {
+    Withdrawn,
+    Normal,
+    Iconic,
+}
+
+/// Read the state field of a window's WM_STATE property.
+pub fn get_wm_state(conn: &impl Connection, window: Window) -> Option<WmState> {
+    let wm_state = conn.intern_atom(false, b"WM_STATE").ok()?.reply().ok()?.atom;
+    let prop = conn
+        .get_property(false, window, wm_state, wm_state, 0, 2)
+        .ok()?
+        .reply()
+        .ok()?;
+
+    let state = prop.value32()?.next()?;
+    match state {
+        0 => Some(WmState::Withdrawn),
+        1 => Some(WmState::Normal),
+        3 => Some(WmState::Iconic),
+        _ => None,
+    }
+}
+
+/// Check if a client is iconified: WM_STATE is Iconic or the EWMH
+/// `_NET_WM_STATE_HIDDEN` state is set.
+pub fn is_iconic(conn: &impl Connection, client: &Client) -> bool {
+    get_wm_state(conn, client.client) == Some(WmState::Iconic)
+        || get_window_state(conn, client.client)
+            .iter()
+            .any(|s| s == "_NET_WM_STATE_HIDDEN")
+}
+
 /// Check if a window has WM_STATE property (indicates it's managed by the WM).
 pub fn has_wm_state(conn: &impl Connection, window: Window) -> bool {
     let wm_state = match conn.intern_atom(false, b"WM_STATE").ok().and_then(|c| c.reply().ok()) {
@@ -88,52 +142,140 @@ pub fn has_wm_state(conn: &impl Connection, window: Window) -> bool {
         .unwrap_or(false)
 }
 
-/// Collect windows in Z-order (most recently used first).
-/// X11 query_tree returns children in bottom-to-top stacking order,
-/// so we reverse to get top-to-bottom (MRU order).
-pub fn collect_windows_by_zorder(conn: &impl Connection, root: Window) -> Vec<(Window, String)> {
-    let tree = match conn.query_tree(root).ok().and_then(|c| c.reply().ok()) {
-        Some(t) => t,
-        None => return Vec::new(),
+/// Collect windows in Z-order (topmost first).
+/// Uses the WM's EWMH client list when available, otherwise walks the tree.
+/// The switcher uses this as the MRU fallback for windows not in the focus history.
+pub fn collect_windows_by_zorder(conn: &impl Connection, root: Window) -> Vec<(Client, String)> {
+    let client_list = match ewmh_wm_check(conn, root) {
+        Ok(()) => get_ewmh_client_list(conn, root),
+        Err(reason) => {
+            log_fmt!("Ignoring EWMH client list: {}", reason);
+            None
+        }
     };
 
-    // Children are in bottom-to-top order, reverse for MRU
-    tree.children
-        .iter()
-        .rev()
-        .filter_map(|&child| find_window_with_title(conn, child, 0))
+    let clients: Vec<Client> = if let Some((source, list)) = client_list {
+        log_fmt!("Window source: {} ({} clients)", source, list.len());
+
+        // Client lists are bottom-to-top (or oldest-first), reverse for topmost first
+        list.into_iter()
+            .rev()
+            .map(|w| Client::from_client(conn, w, root))
+            .collect()
+    } else {
+        log_fmt!("Window source: query_tree walk (no usable EWMH client list)");
+
+        let tree = match conn.query_tree(root).ok().and_then(|c| c.reply().ok()) {
+            Some(t) => t,
+            None => return Vec::new(),
+        };
+
+        // X11 query_tree returns children in bottom-to-top order, reverse for topmost first
+        tree.children
+            .iter()
+            .rev()
+            .filter_map(|&frame| find_client_window(conn, frame).map(|client| Client { frame, client }))
+            .collect()
+    };
+
+    // Iconified clients are unmapped, so keep them regardless of map state
+    clients
+        .into_iter()
+        .filter(|c| is_viewable(conn, c.client) || is_iconic(conn, c))
+        .filter_map(|c| get_window_title(conn, c.client).map(|title| (c, title)))
         .collect()
 }
 
-/// Find a window with a title, searching down the tree.
-/// Returns the window ID that has the title (might be a child).
-fn find_window_with_title(
-    conn: &impl Connection,
-    window: Window,
-    depth: u32,
-) -> Option<(Window, String)> {
-    const MAX_DEPTH: u32 = 10;
+/// Get the WM's client list, preferring `_NET_CLIENT_LIST_STACKING` over
+/// `_NET_CLIENT_LIST`. Only used if the WM advertises it in `_NET_SUPPORTED`;
+/// callers check first that the WM is still running with `ewmh_wm_check`.
+/// Returns the name of the property used along with the client windows.
+fn get_ewmh_client_list(conn: &impl Connection, root: Window) -> Option<(&'static str, Vec<Window>)> {
+    let net_supported = intern(conn, b"_NET_SUPPORTED")?;
+    let supported: Vec<Atom> = conn
+        .get_property(false, root, net_supported, AtomEnum::ATOM, 0, 1024)
+        .ok()?
+        .reply()
+        .ok()?
+        .value32()?
+        .collect();
 
-    if depth > MAX_DEPTH {
-        return None;
-    }
+    for name in ["_NET_CLIENT_LIST_STACKING", "_NET_CLIENT_LIST"] {
+        let atom = match intern(conn, name.as_bytes()) {
+            Some(a) if supported.contains(&a) => a,
+            _ => continue,
+        };
+
+        let prop = match conn
+            .get_property(false, root, atom, AtomEnum::WINDOW, 0, u32::MAX / 4)
+            .ok()
+            .and_then(|c| c.reply().ok())
+        {
+            Some(p) if p.format == 32 => p,
+            _ => continue,
+        };
 
-    // Check if this window is viewable and has a title
-    if is_viewable(conn, window) {
-        if let Some(title) = get_window_title(conn, window) {
-            return Some((window, title));
+        let clients: Option<Vec<Window>> = prop.value32().map(|iter| iter.collect());
+        if let Some(clients) = clients {
+            return Some((name, clients));
         }
     }
 
-    // Search children
+    None
+}
+
+/// Check that an EWMH window manager is running: the root's
+/// `_NET_SUPPORTING_WM_CHECK` must name a live window whose own property
+/// points back to itself. EWMH properties left on the root by a WM that has
+/// since been replaced (e.g. by TWM) fail this check. Returns the reason
+/// the check failed, for logging.
+pub fn ewmh_wm_check(conn: &impl Connection, root: Window) -> Result<(), &'static str> {
+    let check = intern(conn, b"_NET_SUPPORTING_WM_CHECK").ok_or("cannot intern _NET_SUPPORTING_WM_CHECK")?;
+    // None if the window is gone, Some(None) if it lacks the property
+    let read = |window: Window| -> Option<Option<Window>> {
+        let reply = conn
+            .get_property(false, window, check, AtomEnum::WINDOW, 0, 1)
+            .ok()?
+            .reply()
+            .ok()?;
+        Some(reply.value32().and_then(|mut values| values.next()))
+    };
+
+    let wm_window = read(root)
+        .flatten()
+        .ok_or("no _NET_SUPPORTING_WM_CHECK on the root window")?;
+    match read(wm_window) {
+        Some(Some(w)) if w == wm_window => Ok(()),
+        Some(_) => Err("_NET_SUPPORTING_WM_CHECK window does not point back to itself"),
+        None => Err("_NET_SUPPORTING_WM_CHECK window no longer exists"),
+    }
+}
+
+fn intern(conn: &impl Connection, name: &[u8]) -> Option<Atom> {
+    conn.intern_atom(false, name).ok()?.reply().ok().map(|r| r.atom)
+}
+
+/// Find the client window of a top-level, as XmuClientWindow does: the
+/// top-level itself if it has WM_STATE, else the shallowest descendant that does.
+/// Returns None for top-levels that hold no managed client.
+pub fn find_client_window(conn: &impl Connection, toplevel: Window) -> Option<Window> {
+    if has_wm_state(conn, toplevel) {
+        return Some(toplevel);
+    }
+    find_client_in_children(conn, toplevel)
+}
+
+/// Breadth-first by level: check all children for WM_STATE before recursing.
+fn find_client_in_children(conn: &impl Connection, window: Window) -> Option<Window> {
     let tree = conn.query_tree(window).ok()?.reply().ok()?;
-    for child in tree.children {
-        if let Some(result) = find_window_with_title(conn, child, depth + 1) {
-            return Some(result);
-        }
+
+    if let Some(&client) = tree.children.iter().find(|&&child| has_wm_state(conn, child)) {
+        return Some(client);
     }
 
-    None
+    tree.children
+        .iter()
+        .find_map(|&child| find_client_in_children(conn, child))
 }
 
 /// Find the top-level parent of a window (direct child of root).
@@ -157,13 +299,25 @@ pub fn find_toplevel_parent(conn: &impl Connection, window: Window, root: Window
     window
 }
 
-/// Activate a window by raising it and setting input focus.
+/// Activate a window by raising its frame and setting input focus on the client.
+/// Iconified clients are de-iconified first. Returns false if focusing has to
+/// wait until the WM maps the restored client; call `focus_window` then.
 pub fn activate_window(
     conn: &impl Connection,
-    window: Window,
+    client: &Client,
     root: Window,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let toplevel = find_toplevel_parent(conn, window, root);
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if is_iconic(conn, client) {
+        return deiconify_window(conn, client, root);
+    }
+
+    focus_window(conn, client)?;
+    Ok(true)
+}
+
+/// Raise a mapped client's frame and give the client input focus.
+pub fn focus_window(conn: &impl Connection, client: &Client) -> Result<(), Box<dyn std::error::Error>> {
+    let Client { frame: toplevel, client: window } = *client;
 
     log_fmt!("Activating window 0x{:x}, toplevel=0x{:x}", window, toplevel);
 
@@ -188,42 +342,125 @@ pub fn activate_window(
     Ok(())
 }
 
+/// Ask the WM to restore an iconified client. An EWMH WM gets a
+/// `_NET_ACTIVE_WINDOW` request, which also covers clients only hidden with
+/// `_NET_WM_STATE_HIDDEN`, and raises and focuses the client itself.
+/// Otherwise the client window is mapped per ICCCM, which the WM sees as a
+/// MapRequest; it can only be focused once the WM has mapped it, so false
+/// is returned.
+fn deiconify_window(
+    conn: &impl Connection,
+    client: &Client,
+    root: Window,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    /// Source indication for requests from pagers and taskbars.
+    const SOURCE_PAGER: u32 = 2;
+
+    if ewmh_wm_check(conn, root).is_ok() {
+        let net_active_window = intern(conn, b"_NET_ACTIVE_WINDOW").ok_or("cannot intern _NET_ACTIVE_WINDOW")?;
+        log_fmt!("  Restoring 0x{:x} with _NET_ACTIVE_WINDOW", client.client);
+
+        let event = ClientMessageEvent::new(
+            32,
+            client.client,
+            net_active_window,
+            [SOURCE_PAGER, x11rb::CURRENT_TIME, 0, 0, 0],
+        );
+        conn.send_event(
+            false,
+            root,
+            EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY,
+            event,
+        )?;
+        conn.flush()?;
+        return Ok(true);
+    }
+
+    log_fmt!("  De-iconifying 0x{:x}, focusing once it is mapped", client.client);
+    conn.map_window(client.client)?;
+    conn.flush()?;
+    Ok(false)
+}
+
 fn send_take_focus(conn: &impl Connection, window: Window) {
-    let wm_protocols = match conn.intern_atom(false, b"WM_PROTOCOLS").ok().and_then(|c| c.reply().ok()) {
-        Some(r) => r.atom,
-        None => return,
+    // Check if window supports WM_TAKE_FOCUS before sending
+    let Some((wm_protocols, wm_take_focus)) = supported_protocol(conn, window, b"WM_TAKE_FOCUS") else {
+        log_fmt!("  Window does not support WM_TAKE_FOCUS, skipping");
+        return;
     };
 
-    let wm_take_focus = match conn.intern_atom(false, b"WM_TAKE_FOCUS").ok().and_then(|c| c.reply().ok()) {
-        Some(r) => r.atom,
-        None => return,
-    };
+    send_protocol_message(conn, window, wm_protocols, wm_take_focus);
+}
 
-    // Check if window supports WM_TAKE_FOCUS before sending
-    let prop = match conn
+/// Close a client politely with WM_DELETE_WINDOW, or disconnect it with
+/// KillClient if it doesn't support the protocol.
+pub fn close_window(conn: &impl Connection, client: &Client) -> Result<(), Box<dyn std::error::Error>> {
+    match supported_protocol(conn, client.client, b"WM_DELETE_WINDOW") {
+        Some((wm_protocols, wm_delete_window)) => {
+            log_fmt!("Closing 0x{:x} with WM_DELETE_WINDOW", client.client);
+            send_protocol_message(conn, client.client, wm_protocols, wm_delete_window);
+        }
+        None => {
+            log_fmt!("0x{:x} does not support WM_DELETE_WINDOW, killing client", client.client);
+            conn.kill_client(client.client)?;
+        }
+    }
+    conn.flush()?;
+    Ok(())
+}
+
+/// Forcibly disconnect the X client owning a window.
+pub fn kill_window(conn: &impl Connection, client: &Client) -> Result<(), Box<dyn std::error::Error>> {
+    log_fmt!("Killing client of 0x{:x}", client.client);
+    conn.kill_client(client.client)?;
+    conn.flush()?;
+    Ok(())
+}
+
+/// Ask the WM to iconify a client with an ICCCM WM_CHANGE_STATE message.
+pub fn iconify_window(conn: &impl Connection, client: &Client, root: Window) -> Result<(), Box<dyn std::error::Error>> {
+    const ICONIC_STATE: u32 = 3;
+
+    let wm_change_state = intern(conn, b"WM_CHANGE_STATE").ok_or("cannot intern WM_CHANGE_STATE")?;
+    log_fmt!("Iconifying 0x{:x}", client.client);
+
+    let event = ClientMessageEvent::new(32, client.client, wm_change_state, [ICONIC_STATE, 0, 0, 0, 0]);
+    conn.send_event(
+        false,
+        root,
+        EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY,
+        event,
+    )?;
+    conn.flush()?;
+    Ok(())
+}
+
+/// Check whether a window lists `protocol` in its WM_PROTOCOLS property.
+/// Returns the WM_PROTOCOLS and protocol atoms if it does.
+fn supported_protocol(conn: &impl Connection, window: Window, protocol: &[u8]) -> Option<(Atom, Atom)> {
+    let wm_protocols = conn.intern_atom(false, b"WM_PROTOCOLS").ok()?.reply().ok()?.atom;
+    let protocol = conn.intern_atom(false, protocol).ok()?.reply().ok()?.atom;
+
+    let prop = conn
         .get_property(false, window, wm_protocols, AtomEnum::ATOM, 0, 32)
-        .ok()
-        .and_then(|c| c.reply().ok())
-    {
-        Some(p) => p,
-        None => return,
-    };
+        .ok()?
+        .reply()
+        .ok()?;
 
-    let supports_take_focus = prop
+    let supported = prop
         .value32()
-        .map(|atoms| atoms.into_iter().any(|a| a == wm_take_focus))
+        .map(|mut atoms| atoms.any(|a| a == protocol))
         .unwrap_or(false);
 
-    if !supports_take_focus {
-        log_fmt!("  Window does not support WM_TAKE_FOCUS, skipping");
-        return;
-    }
+    supported.then_some((wm_protocols, protocol))
+}
 
+fn send_protocol_message(conn: &impl Connection, window: Window, wm_protocols: Atom, protocol: Atom) {
     let event = ClientMessageEvent::new(
         32,
         window,
         wm_protocols,
-        [wm_take_focus, x11rb::CURRENT_TIME, 0, 0, 0],
+        [protocol, x11rb::CURRENT_TIME, 0, 0, 0],
     );
 
     let _ = conn.send_event(false, window, EventMask::NO_EVENT, event);
@@ -296,23 +533,25 @@ fn atom_name(conn: &impl Connection, atom: Atom) -> Option<String> {
     String::from_utf8(reply.name).ok()
 }
 
-/// Check if a window should be shown in the switcher.
-/// Only shows windows that have WM_STATE (managed by the window manager).
+/// Check if a client should be shown in the switcher.
+/// Only shows clients that have WM_STATE (managed by the window manager).
 /// Returns (should_show, reason) tuple for logging purposes.
-pub fn should_show_in_switcher(conn: &impl Connection, window: Window) -> (bool, &'static str) {
-    if has_wm_state(conn, window) {
+pub fn should_show_in_switcher(conn: &impl Connection, client: &Client) -> (bool, &'static str) {
+    if has_wm_state(conn, client.client) {
         (true, "has WM_STATE")
     } else {
         (false, "no WM_STATE (not managed by WM)")
     }
 }
 
-/// Log detailed debug info about a window.
-pub fn log_window_debug_info(conn: &impl Connection, window: Window, root: Window) {
+/// Log detailed debug info about a client.
+pub fn log_window_debug_info(conn: &impl Connection, client: &Client) {
     if !log::is_enabled() {
         return;
     }
 
+    let window = client.client;
+
     let title = get_window_title(conn, window).unwrap_or_else(|| "(no title)".into());
     let class = get_wm_class(conn, window)
         .map(|(i, c)| format!("{} / {}", i, c))
@@ -320,16 +559,15 @@ pub fn log_window_debug_info(conn: &impl Connection, window: Window, root: Windo
     let types = get_window_type(conn, window);
     let states = get_window_state(conn, window);
     let viewable = is_viewable(conn, window);
-    let wm_state = has_wm_state(conn, window);
-    let toplevel = find_toplevel_parent(conn, window, root);
-    let (should_show, reason) = should_show_in_switcher(conn, window);
+    let wm_state = get_wm_state(conn, window);
+    let (should_show, reason) = should_show_in_switcher(conn, client);
 
     log_fmt!("Window 0x{:x}:", window);
     log_fmt!("  Title: {}", title);
     log_fmt!("  Class: {}", class);
     log_fmt!("  Type: {:?}", types);
     log_fmt!("  State: {:?}", states);
-    log_fmt!("  Viewable: {}, WM_STATE: {}", viewable, wm_state);
-    log_fmt!("  TopLevel: 0x{:x}", toplevel);
+    log_fmt!("  Viewable: {}, WM_STATE: {:?}", viewable, wm_state);
+    log_fmt!("  Frame: 0x{:x}", client.frame);
     log_fmt!("  ShouldShow: {} ({})", should_show, reason);
 }